@@ -0,0 +1,341 @@
+use rocket::figment::Figment;
+use rocket::figment::providers::Env;
+use serde_derive::Deserialize;
+use std::time::Duration;
+use crate::services::codec::CodecKind;
+use crate::services::subscription::SubscriptionSettings;
+use crate::services::trash::TrashSettings;
+use crate::services::todo::{DEFAULT_BATCH_SIZE, DEFAULT_SNAPSHOT_INTERVAL, MAX_BATCH_SIZE};
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum StorageBackend {
+    Mongo,
+    Sqlite,
+    Memory,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct MongoConfig {
+    pub uri: String,
+    pub database: String,
+    pub todo_collection: String,
+    /// One document per event.
+    pub event_collection: String,
+    pub snapshot_collection: String,
+    /// Last processed position of every subscriber.
+    pub checkpoint_collection: String,
+    /// Counter documents, such as the last handed out event position and the lease of the
+    /// one instance that may append events.
+    pub counter_collection: String,
+    /// Streams stored as one document per todo, migrated into `event_collection` on startup.
+    pub legacy_event_collection: String,
+    /// Codec new events are written with.
+    pub codec: CodecKind,
+}
+
+impl Default for MongoConfig {
+    fn default() -> MongoConfig {
+        MongoConfig {
+            uri: "mongodb://localhost:27017".to_string(),
+            database: "rust-test".to_string(),
+            todo_collection: "todo".to_string(),
+            event_collection: "todo-stream-events".to_string(),
+            snapshot_collection: "todo-snapshots".to_string(),
+            checkpoint_collection: "todo-checkpoints".to_string(),
+            counter_collection: "todo-counters".to_string(),
+            legacy_event_collection: "todo-events".to_string(),
+            codec: CodecKind::Bson,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct SqliteConfig {
+    /// Database file, created on first start. Both the events and the read model live in it.
+    pub path: String,
+    /// Codec new events are written with.
+    pub codec: CodecKind,
+}
+
+impl Default for SqliteConfig {
+    fn default() -> SqliteConfig {
+        SqliteConfig {
+            path: "todo.db".to_string(),
+            codec: CodecKind::Json,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct MemoryConfig {
+    /// Codec events are kept in.
+    pub codec: CodecKind,
+}
+
+impl Default for MemoryConfig {
+    fn default() -> MemoryConfig {
+        MemoryConfig {
+            codec: CodecKind::Json
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct SnapshotConfig {
+    /// Snapshot a todo every this many events. 0 disables snapshots.
+    pub interval: u32,
+}
+
+impl Default for SnapshotConfig {
+    fn default() -> SnapshotConfig {
+        SnapshotConfig {
+            interval: DEFAULT_SNAPSHOT_INTERVAL
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct SubscriptionConfig {
+    /// How many events a subscriber reads from the log at once.
+    pub batch_size: usize,
+    /// How long an idle subscriber waits before checking the log again, in milliseconds.
+    pub poll_interval_ms: u64,
+}
+
+impl Default for SubscriptionConfig {
+    fn default() -> SubscriptionConfig {
+        SubscriptionConfig {
+            batch_size: DEFAULT_BATCH_SIZE,
+            poll_interval_ms: 1000,
+        }
+    }
+}
+
+impl SubscriptionConfig {
+    pub fn settings(&self) -> SubscriptionSettings {
+        SubscriptionSettings {
+            batch_size: self.batch_size,
+            poll_interval: Duration::from_millis(self.poll_interval_ms),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct TrashConfig {
+    /// How many days a deleted todo can be restored before it is purged for good.
+    pub retention_days: u32,
+    /// How often expired todos are purged, in seconds.
+    pub purge_interval_secs: u64,
+}
+
+impl Default for TrashConfig {
+    fn default() -> TrashConfig {
+        TrashConfig {
+            retention_days: 30,
+            purge_interval_secs: 3600,
+        }
+    }
+}
+
+impl TrashConfig {
+    pub fn settings(&self) -> TrashSettings {
+        TrashSettings {
+            retention: chrono::Duration::days(self.retention_days.into()),
+            purge_interval: Duration::from_secs(self.purge_interval_secs),
+        }
+    }
+}
+
+/// Settings under the `todo` key. Values come from Rocket.toml, then `ROCKET_TODO` and
+/// `TODO_`-prefixed environment variables, where `__` separates nested keys
+/// (`TODO_MONGO__URI`). A `.env` file is loaded into the environment first.
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct AppConfig {
+    pub storage: StorageBackend,
+    pub mongo: MongoConfig,
+    pub sqlite: SqliteConfig,
+    pub memory: MemoryConfig,
+    pub snapshots: SnapshotConfig,
+    pub subscriptions: SubscriptionConfig,
+    pub trash: TrashConfig,
+}
+
+impl Default for AppConfig {
+    fn default() -> AppConfig {
+        AppConfig {
+            storage: StorageBackend::Mongo,
+            mongo: MongoConfig::default(),
+            sqlite: SqliteConfig::default(),
+            memory: MemoryConfig::default(),
+            snapshots: SnapshotConfig::default(),
+            subscriptions: SubscriptionConfig::default(),
+            trash: TrashConfig::default(),
+        }
+    }
+}
+
+/// Rocket's own configuration sources plus the `TODO_` environment variables. The same
+/// figment is handed to Rocket so both read one consistent set of values.
+pub fn figment() -> Figment {
+    dotenv::dotenv().ok();
+
+    rocket::Config::figment()
+        .merge(Env::prefixed("TODO_").split("__").map(|key| format!("todo.{}", key).into()))
+}
+
+impl AppConfig {
+    /// Extracts and validates the settings, collecting every problem instead of stopping at
+    /// the first one.
+    pub fn from_figment(figment: &Figment) -> Result<AppConfig, Vec<String>> {
+        let config: AppConfig = match figment.find_value("todo") {
+            Err(_) => { AppConfig::default() }
+            Ok(_) => {
+                figment
+                    .extract_inner("todo")
+                    .map_err(|err| err.into_iter().map(|err| err.to_string()).collect::<Vec<_>>())?
+            }
+        };
+
+        let errors = config.validate();
+        if errors.is_empty() {
+            Ok(config)
+        } else {
+            Err(errors)
+        }
+    }
+
+    fn validate(&self) -> Vec<String> {
+        let mut errors = match self.storage {
+            StorageBackend::Mongo => { self.validate_mongo() }
+            StorageBackend::Sqlite => { self.validate_sqlite() }
+            StorageBackend::Memory => { vec![] }
+        };
+        let subscriptions = &self.subscriptions;
+        if subscriptions.batch_size == 0 || subscriptions.batch_size > MAX_BATCH_SIZE {
+            errors.push(format!("todo.subscriptions.batch_size must be between 1 and {}", MAX_BATCH_SIZE));
+        }
+        if subscriptions.poll_interval_ms == 0 {
+            errors.push("todo.subscriptions.poll_interval_ms must be greater than 0".to_string());
+        }
+        if self.trash.purge_interval_secs == 0 {
+            errors.push("todo.trash.purge_interval_secs must be greater than 0".to_string());
+        }
+
+        errors
+    }
+
+    fn validate_sqlite(&self) -> Vec<String> {
+        if self.sqlite.path.trim().is_empty() {
+            vec!["todo.sqlite.path must not be empty".to_string()]
+        } else {
+            vec![]
+        }
+    }
+
+    fn validate_mongo(&self) -> Vec<String> {
+        let mut errors = vec![];
+        let mongo = &self.mongo;
+        if !mongo.uri.starts_with("mongodb://") && !mongo.uri.starts_with("mongodb+srv://") {
+            errors.push(format!("todo.mongo.uri must start with mongodb:// or mongodb+srv://, got {:?}", mongo.uri));
+        }
+        let names = [
+            ("todo.mongo.database", &mongo.database),
+            ("todo.mongo.todo_collection", &mongo.todo_collection),
+            ("todo.mongo.event_collection", &mongo.event_collection),
+            ("todo.mongo.snapshot_collection", &mongo.snapshot_collection),
+            ("todo.mongo.checkpoint_collection", &mongo.checkpoint_collection),
+            ("todo.mongo.counter_collection", &mongo.counter_collection),
+            ("todo.mongo.legacy_event_collection", &mongo.legacy_event_collection),
+        ];
+        for (key, name) in names {
+            if name.trim().is_empty() {
+                errors.push(format!("{} must not be empty", key));
+            }
+        }
+        let collections = [
+            &mongo.todo_collection,
+            &mongo.event_collection,
+            &mongo.snapshot_collection,
+            &mongo.checkpoint_collection,
+            &mongo.counter_collection,
+            &mongo.legacy_event_collection,
+        ];
+        for (i, collection) in collections.iter().enumerate() {
+            if collections[..i].contains(collection) {
+                errors.push(format!("todo.mongo collection {:?} is configured more than once", collection));
+            }
+        }
+
+        errors
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rocket::figment::Figment;
+    use serde_json::{json, Value};
+    use crate::config::{AppConfig, StorageBackend};
+
+    fn config(values: &[(&str, Value)]) -> Result<AppConfig, Vec<String>> {
+        let figment = values
+            .iter()
+            .fold(Figment::new(), |figment, (key, value)| figment.merge((*key, value)));
+
+        AppConfig::from_figment(&figment)
+    }
+
+    #[test]
+    fn defaults_apply_without_a_todo_section() {
+        let defaults = config(&[]).unwrap();
+        assert_eq!(defaults.storage, StorageBackend::Mongo);
+        assert_eq!(defaults.mongo.uri, "mongodb://localhost:27017");
+        assert_eq!(defaults.trash.retention_days, 30);
+
+        let sqlite = config(&[("todo.storage", json!("sqlite")), ("todo.sqlite.path", json!("todos.db"))]).unwrap();
+        assert_eq!((sqlite.storage, sqlite.sqlite.path.as_str()), (StorageBackend::Sqlite, "todos.db"));
+    }
+
+    #[test]
+    fn rejects_an_unknown_backend() {
+        let errors = config(&[("todo.storage", json!("postgres"))]).unwrap_err();
+        assert!(errors[0].contains("postgres"), "{:?}", errors);
+    }
+
+    #[test]
+    fn rejects_empty_paths_and_names() {
+        assert_eq!(
+            config(&[("todo.storage", json!("sqlite")), ("todo.sqlite.path", json!(" "))]).unwrap_err(),
+            vec!["todo.sqlite.path must not be empty"],
+        );
+
+        let errors = config(&[
+            ("todo.mongo.uri", json!("")),
+            ("todo.mongo.database", json!("")),
+            ("todo.mongo.snapshot_collection", json!("todo")),
+        ]).unwrap_err();
+        assert_eq!(errors, vec![
+            "todo.mongo.uri must start with mongodb:// or mongodb+srv://, got \"\"",
+            "todo.mongo.database must not be empty",
+            "todo.mongo collection \"todo\" is configured more than once",
+        ]);
+    }
+
+    #[test]
+    fn collects_every_invalid_setting() {
+        let errors = config(&[
+            ("todo.storage", json!("memory")),
+            ("todo.subscriptions.batch_size", json!(0)),
+            ("todo.subscriptions.poll_interval_ms", json!(0)),
+            ("todo.trash.purge_interval_secs", json!(0)),
+        ]).unwrap_err();
+        assert_eq!(errors.len(), 3, "{:?}", errors);
+    }
+}
@@ -5,7 +5,7 @@ use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use serde::de::{Error, Visitor};
 use uuid::{Uuid};
 
-#[derive(Clone, Copy, PartialOrd, PartialEq, Ord, Eq)]
+#[derive(Clone, Copy, PartialOrd, PartialEq, Ord, Eq, Hash)]
 pub struct Guid {
     val: Uuid,
 }
@@ -42,22 +42,6 @@ impl Guid {
         }
     }
 
-    pub fn from_string(val: &String) -> Result<Guid, String> {
-        let uuid = Uuid::from_str(val.as_str());
-        match uuid {
-            Ok(val) => {
-                Ok(Guid {
-                    val
-                })
-            }
-            Err(_) => { Err("Could not parse id".to_string()) }
-        }
-    }
-
-    pub fn to_string(&self) -> String {
-        self.val.to_string()
-    }
-    
 }
 
 impl Display for Guid {
@@ -74,11 +58,7 @@ impl Serialize for Guid {
 
 impl<'de> Deserialize<'de> for Guid {
     fn deserialize<'d, D>(deserializer: D) -> Result<Self, D::Error> where D: Deserializer<'de> {
-        let val: String = Deserialize::deserialize(deserializer)?;
-        let guid = Guid::from_string(&val).unwrap();
-        Ok(guid)
-        // const FIELDS: &'static [&'static str] = &["val"];
-        // deserializer.deserialize_struct("Guid", FIELDS, GuidVisitor)
+        deserializer.deserialize_str(GuidVisitor)
     }
 }
 
@@ -100,7 +80,7 @@ impl<'de> Visitor<'de> for GuidVisitor {
     }
 
     fn visit_string<E>(self, v: String) -> Result<Self::Value, E> where E: Error {
-        Guid::from_string(&v).map_err(|x| Error::custom(x))
+        Guid::from_str(&v).map_err(|x| Error::custom(x))
     }
 }
 
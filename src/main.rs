@@ -1,20 +1,75 @@
 mod routes;
 mod services;
 mod guid;
+mod config;
 
 #[macro_use]
 extern crate rocket;
 
 use rocket::{Build, Rocket};
-use uuid::Uuid;
-use crate::guid::Guid;
+use crate::config::{AppConfig, StorageBackend};
 use crate::routes::todo::AddTodo;
 use crate::services::create_mongo_client;
+use crate::services::data::{InMemoryTodoRepository, MongoTodoRepository};
+use crate::services::event_store::{InMemoryEventStore, MongoEventStore};
+use crate::services::sqlite::{SqliteEventStore, SqliteTodoRepository};
+use crate::services::subscription::{ActivityLog, Subscriber};
+
+fn exit_with_config_errors(errors: Vec<String>) -> ! {
+    eprintln!("Invalid configuration:");
+    for error in errors {
+        eprintln!("  - {}", error);
+    }
+    std::process::exit(1)
+}
+
+/// Background consumers of the event log, each resuming from its own checkpoint.
+fn subscribers() -> Vec<Subscriber> {
+    vec![
+        Subscriber::new("activity-log", Box::new(ActivityLog)),
+    ]
+}
 
 #[launch]
 async fn rocket() -> Rocket<Build> {
-    let builder = rocket::build();
-    let mongodb = &create_mongo_client().await;
-        
-    builder.add_todo(mongodb).await
-}
\ No newline at end of file
+    let figment = config::figment();
+    let config = AppConfig::from_figment(&figment).unwrap_or_else(|errors| exit_with_config_errors(errors));
+    let builder = rocket::custom(figment);
+
+    match config.storage {
+        StorageBackend::Memory => {
+            builder.add_todo(
+                Box::new(InMemoryTodoRepository::new()),
+                Box::new(InMemoryEventStore::with_codec(config.memory.codec.codec())),
+                config.snapshots.interval,
+                subscribers(),
+                config.subscriptions.settings(),
+                config.trash.settings(),
+            ).await
+        }
+        StorageBackend::Mongo => {
+            let mongodb = &create_mongo_client(&config.mongo.uri).await
+                .unwrap_or_else(|error| exit_with_config_errors(vec![error]));
+            let todo_repo = MongoTodoRepository::init(mongodb, &config.mongo).await
+                .unwrap_or_else(|error| exit_with_config_errors(vec![error]));
+            let event_repo = MongoEventStore::init(mongodb, &config.mongo).await
+                .unwrap_or_else(|error| exit_with_config_errors(vec![error]));
+            match event_repo.migrate_legacy_streams().await {
+                Ok(0) => {}
+                Ok(migrated) => { log::info!("Migrated {} todo event streams to per-event documents", migrated); }
+                Err(err) => { exit_with_config_errors(vec![format!("Could not migrate legacy events: {}", err)]) }
+            }
+
+            builder.add_todo(Box::new(todo_repo), Box::new(event_repo), config.snapshots.interval, subscribers(), config.subscriptions.settings(), config.trash.settings()).await
+        }
+        StorageBackend::Sqlite => {
+            let path = &config.sqlite.path;
+            let todo_repo = SqliteTodoRepository::open(path)
+                .unwrap_or_else(|error| exit_with_config_errors(vec![error]));
+            let event_repo = SqliteEventStore::open(path, config.sqlite.codec.codec())
+                .unwrap_or_else(|error| exit_with_config_errors(vec![error]));
+
+            builder.add_todo(Box::new(todo_repo), Box::new(event_repo), config.snapshots.interval, subscribers(), config.subscriptions.settings(), config.trash.settings()).await
+        }
+    }
+}
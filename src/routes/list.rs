@@ -0,0 +1,93 @@
+use rocket::serde::json::Json;
+use rocket::State;
+use serde_derive::{Deserialize, Serialize};
+use crate::guid::Guid;
+use crate::routes::todo::{ActionResult, Todo, TodoErrResponder};
+use crate::services::aggregate::Aggregate;
+use crate::services::event_store::EventMetadata;
+use crate::services::list::{TodoList, TodoListCommand};
+use crate::services::todo::TodoService;
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct List {
+    pub id: Guid,
+    pub name: String,
+    pub archived: bool,
+    pub todos: Vec<Guid>,
+    pub version: u32,
+}
+
+impl List {
+    pub fn from_agg(agg: TodoList) -> List {
+        List {
+            version: agg.version(),
+            id: agg.id,
+            name: agg.name,
+            archived: agg.archived,
+            todos: agg.todos,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CreateListRequest {
+    pub name: String,
+}
+
+#[get("/")]
+pub async fn list_lists(service: &State<TodoService>) -> ActionResult<Vec<List>> {
+    let lists = service
+        .list_lists().await
+        .map_err(TodoErrResponder::new)?
+        .into_iter()
+        .map(List::from_agg)
+        .collect();
+
+    Ok(Json(lists))
+}
+
+#[get("/<id>")]
+pub async fn get_list(id: Guid, service: &State<TodoService>) -> ActionResult<List> {
+    let list = service.get_list(id).await.map_err(TodoErrResponder::new)?;
+
+    Ok(Json(List::from_agg(list)))
+}
+
+#[post("/", format = "json", data = "<name>")]
+pub async fn create_list(name: Json<CreateListRequest>, metadata: EventMetadata, service: &State<TodoService>) -> ActionResult<List> {
+    let list = service.create_list(name.into_inner().name, &metadata).await.map_err(TodoErrResponder::new)?;
+
+    Ok(Json(List::from_agg(list)))
+}
+
+/// Runs a `TodoListCommand` such as `{"type": "RenameList", "name": "..."}` or
+/// `{"type": "ArchiveList"}` against the list.
+#[patch("/<id>?<expected_version>", format = "json", data = "<command>")]
+pub async fn update_list(id: Guid, expected_version: Option<u32>, command: Json<TodoListCommand>, metadata: EventMetadata, service: &State<TodoService>) -> ActionResult<List> {
+    let list = service.update_list(id, command.into_inner(), expected_version, &metadata).await.map_err(TodoErrResponder::new)?;
+
+    Ok(Json(List::from_agg(list)))
+}
+
+#[get("/<id>/todos")]
+pub async fn list_todos(id: Guid, service: &State<TodoService>) -> ActionResult<Vec<Todo>> {
+    let todos = service.list_todos(id).await.map_err(TodoErrResponder::new)?;
+
+    Ok(Json(todos))
+}
+
+/// Moves the todo into this list, out of whichever list it was in before.
+#[put("/<id>/todos/<todo_id>")]
+pub async fn move_todo(id: Guid, todo_id: Guid, metadata: EventMetadata, service: &State<TodoService>) -> ActionResult<Todo> {
+    let agg = service.move_task(todo_id, Some(id), &metadata).await.map_err(TodoErrResponder::new)?;
+
+    Ok(Json(service.todo(agg)))
+}
+
+/// Takes the todo out of this list without putting it in another one.
+#[delete("/<id>/todos/<todo_id>")]
+pub async fn remove_todo(id: Guid, todo_id: Guid, metadata: EventMetadata, service: &State<TodoService>) -> ActionResult<Todo> {
+    let agg = service.remove_from_list(id, todo_id, &metadata).await.map_err(TodoErrResponder::new)?;
+
+    Ok(Json(service.todo(agg)))
+}
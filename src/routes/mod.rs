@@ -1,2 +1,3 @@
 pub mod todo;
+pub mod list;
 pub mod responders;
\ No newline at end of file
@@ -1,40 +1,126 @@
-use mongodb::Client;
+use chrono::{DateTime, Duration, Utc};
 use rocket::serde::json::Json;
 use rocket::{Build, Request, Rocket, State};
-use rocket::http::{ContentType, Header};
+use rocket::request::{FromRequest, Outcome};
+use rocket::http::Status as HttpStatus;
+use rocket::response::{self, Responder};
+use rocket::response::stream::{Event, EventStream};
+use rocket::Shutdown;
+use rocket::fairing::AdHoc;
+use rocket::tokio::select;
+use rocket::tokio::sync::broadcast::error::RecvError;
 use serde_derive::{Deserialize, Serialize};
+use serde_json::Value;
 use crate::guid::Guid;
-use crate::services::aggregate::{Aggregate, TodoAggregate, TodoEvent};
-use crate::services::data::MongoTodoRepository;
-use crate::services::event_store::TodoEventCollRepo;
-use crate::services::todo::{TodoService, TodoServiceErr};
+use crate::routes::list;
+use crate::services::aggregate::{Aggregate, TodoAggregate, TodoCommand, TodoEvent};
+use crate::services::clock::SystemClock;
+use crate::services::data::TodoRepository;
+use crate::services::event_store::{EventMetadata, EventStore, RecordedEvent};
+use crate::services::projection::PROJECTION_SUBSCRIBER;
+use crate::services::subscription::{Subscriber, Subscriptions, SubscriptionSettings, SubscriptionStatus};
+use crate::services::trash::TrashSettings;
+use crate::services::todo::{PointInTime, TodoFilter, TodoService, TodoServiceErr, DEFAULT_BATCH_SIZE};
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
 pub struct Todo {
     pub id: Guid,
     pub status: Status,
     pub name: String,
+    #[serde(default)]
+    pub due: Option<DateTime<Utc>>,
+    /// Whether the todo is open past its due date. Worked out whenever a todo is read, so the
+    /// stored value is never relied on.
+    #[serde(default)]
+    pub overdue: bool,
+    /// The list the todo belongs to, if any.
+    #[serde(default)]
+    pub list: Option<Guid>,
+    /// The todo this one is a subtask of.
+    #[serde(default)]
+    pub parent: Option<Guid>,
     pub version: u32,
+    /// When the todo was deleted. Deleted todos stay in the read model, as the trash, until
+    /// they are purged.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub deleted_at: Option<DateTime<Utc>>,
 }
 
 impl Todo {
-    pub fn new(name: String) -> Todo {
-        Todo {
-            name,
-            status: Status::Incomplete,
-            id: Guid::new(),
-            version: 0,
-        }
-    }
-
     pub fn from_agg(agg: TodoAggregate) -> Todo {
         Todo {
             version: agg.version(),
             id: agg.id,
             status: agg.status,
             name: agg.name,
+            due: agg.due,
+            overdue: false,
+            list: agg.list,
+            parent: agg.parent,
+            deleted_at: None,
         }
     }
+
+    pub fn is_overdue(&self, now: DateTime<Utc>) -> bool {
+        !self.status.is_done() && self.due.is_some_and(|due| due < now)
+    }
+}
+
+/// A todo with its subtasks, nested as deep as they go.
+#[derive(Debug, Serialize)]
+pub struct TodoTree {
+    #[serde(flatten)]
+    pub todo: Todo,
+    /// Percentage of the subtasks that are complete, where an open subtask counts with its own
+    /// completion. `None` without subtasks.
+    pub completion: Option<u8>,
+    pub children: Vec<TodoTree>,
+}
+
+/// A stored event together with the version it produced. `Create` events carry no version
+/// field of their own, so it is always spelled out here.
+#[derive(Debug, Serialize)]
+pub struct TodoHistoryEntry {
+    pub version: u32,
+    pub event_id: Guid,
+    pub recorded_at: DateTime<Utc>,
+    pub actor: Option<String>,
+    pub correlation_id: Option<String>,
+    pub causation_id: Option<String>,
+    pub event: TodoEvent,
+}
+
+/// The position of the last change a reconnecting SSE client saw, from `Last-Event-ID`.
+pub struct LastEventId(Option<u64>);
+
+#[async_trait]
+impl<'r> FromRequest<'r> for LastEventId {
+    type Error = ();
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let position = req.headers()
+            .get_one("Last-Event-ID")
+            .and_then(|value| value.trim().parse().ok());
+
+        Outcome::Success(LastEventId(position))
+    }
+}
+
+/// Reads event metadata from the `X-Actor`, `X-Correlation-Id` and `X-Causation-Id` headers.
+/// Requests without a correlation id start a new correlation.
+#[async_trait]
+impl<'r> FromRequest<'r> for EventMetadata {
+    type Error = ();
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let header = |name: &str| req.headers().get_one(name).map(|value| value.to_string());
+
+        Outcome::Success(EventMetadata {
+            actor: header("X-Actor"),
+            correlation_id: header("X-Correlation-Id").or_else(|| Some(Guid::new().to_string())),
+            causation_id: header("X-Causation-Id"),
+        })
+    }
 }
 
 #[derive(Debug, Deserialize)]
@@ -42,108 +128,401 @@ pub struct CreateTodoRequest {
     pub name: String,
 }
 
-#[derive(Debug, Deserialize, Serialize, Clone)]
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
 #[serde(tag = "type")]
 pub enum Status {
     Complete,
     Incomplete,
+    InProgress,
+    Blocked,
+    Cancelled,
+}
+
+impl Status {
+    /// Whether nothing is left to do, either because the todo is complete or was cancelled.
+    pub fn is_done(&self) -> bool {
+        matches!(self, Status::Complete | Status::Cancelled)
+    }
 }
 
 #[derive(Deserialize, Serialize)]
 struct TodoError {
+    code: String,
     message: String,
+    /// Subtasks a partially applied cascade did complete.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    completed: Vec<Guid>,
 }
 
 impl TodoError {
-    fn new(message: &str) -> TodoError {
+    fn new(code: &str, message: &str) -> TodoError {
         TodoError {
-            message: message.to_string()
+            code: code.to_string(),
+            message: message.to_string(),
+            completed: vec![],
         }
     }
 }
 
-#[derive(Responder)]
-#[response(status = 400, content_type = "json")]
 pub struct TodoErrResponder {
-    inner: Json<TodoServiceErr>,
+    err: TodoServiceErr,
 }
 
 impl TodoErrResponder {
     pub fn new(err: TodoServiceErr) -> TodoErrResponder {
         TodoErrResponder {
-            inner: Json(err)
+            err
+        }
+    }
+
+    fn status(&self) -> HttpStatus {
+        match self.err {
+            TodoServiceErr::NotFound(_) => { HttpStatus::NotFound }
+            TodoServiceErr::Conflict(_) => { HttpStatus::Conflict }
+            TodoServiceErr::Deleted(_) => { HttpStatus::Gone }
+            TodoServiceErr::Validation(_) => { HttpStatus::UnprocessableEntity }
+            TodoServiceErr::Rejected(_) => { HttpStatus::Conflict }
+            TodoServiceErr::StorageUnavailable(_) => { HttpStatus::ServiceUnavailable }
+            TodoServiceErr::PartiallyApplied { .. } => { HttpStatus::Conflict }
+        }
+    }
+}
+
+impl<'r> Responder<'r, 'static> for TodoErrResponder {
+    fn respond_to(self, req: &'r Request<'_>) -> response::Result<'static> {
+        let status = self.status();
+        let mut body = TodoError::new(self.err.code(), self.err.message());
+        if let TodoServiceErr::PartiallyApplied { completed, .. } = self.err {
+            body.completed = completed;
         }
+
+        (status, Json(body)).respond_to(req)
     }
 }
 
 pub type ActionResult<T> = Result<Json<T>, TodoErrResponder>;
 
 #[catch(422)]
-async fn catch_malformed_request(req: &Request<'_>) -> Json<TodoError> {
-    Json(TodoError::new("Could not parse request"))
+async fn catch_malformed_request(_req: &Request<'_>) -> Json<TodoError> {
+    Json(TodoError::new("malformed_request", "Could not parse request"))
+}
+
+fn parse_timestamp(name: &str, value: &str) -> Result<DateTime<Utc>, TodoErrResponder> {
+    DateTime::parse_from_rfc3339(value)
+        .map(|timestamp| timestamp.with_timezone(&Utc))
+        .map_err(|_| TodoErrResponder::new(TodoServiceErr::Validation(format!("{} must be an RFC3339 timestamp", name))))
+}
+
+/// Parses an ISO 8601 duration made of weeks, days, hours, minutes and seconds, such as
+/// `P3D`, `P1W` or `PT12H30M`. Years and months have no fixed length and are not accepted.
+fn parse_duration(value: &str) -> Result<Duration, String> {
+    let invalid = || format!("{} is not an ISO 8601 duration such as P3D or PT12H", value);
+    let rest = value.strip_prefix('P').ok_or_else(invalid)?;
+    let (date, time) = match rest.split_once('T') {
+        None => { (rest, None) }
+        Some((date, time)) => { (date, Some(time)) }
+    };
+    if time == Some("") || (date.is_empty() && time.is_none()) {
+        return Err(invalid());
+    }
+
+    let mut seconds: i64 = 0;
+    for (part, units) in [(date, &[('W', 604_800), ('D', 86_400)][..]), (time.unwrap_or(""), &[('H', 3_600), ('M', 60), ('S', 1)][..])] {
+        let mut number = String::new();
+        for c in part.chars() {
+            if c.is_ascii_digit() {
+                number.push(c);
+                continue;
+            }
+            let unit = units.iter().find(|(name, _)| *name == c).ok_or_else(invalid)?.1;
+            let amount: i64 = number.parse().map_err(|_| invalid())?;
+            seconds = amount
+                .checked_mul(unit)
+                .and_then(|amount| seconds.checked_add(amount))
+                .ok_or_else(invalid)?;
+            number.clear();
+        }
+        if !number.is_empty() {
+            return Err(invalid());
+        }
+    }
+
+    Duration::try_seconds(seconds).ok_or_else(invalid)
 }
 
-#[get("/")]
-pub async fn list_tasks(service: &State<TodoService>) -> ActionResult<Vec<Todo>> {
+/// Lists the current todos. `due_before` (RFC3339), `overdue` and `due_within` (an ISO 8601
+/// duration such as `P3D`) narrow the list down by due date.
+#[get("/?<due_before>&<overdue>&<due_within>")]
+pub async fn list_tasks(due_before: Option<&str>, overdue: Option<bool>, due_within: Option<&str>, service: &State<TodoService>) -> ActionResult<Vec<Todo>> {
+    let filter = TodoFilter {
+        due_before: due_before.map(|value| parse_timestamp("due_before", value)).transpose()?,
+        overdue,
+        due_within: due_within
+            .map(|value| parse_duration(value).map_err(|err| TodoErrResponder::new(TodoServiceErr::Validation(err))))
+            .transpose()?,
+    };
     let todos = service
-        .list_tasks().await
-        .map_err(TodoErrResponder::new)?
-        .into_iter()
-        .map(|agg| Todo::from_agg(agg))
-        .collect();
+        .list_tasks(&filter).await
+        .map_err(TodoErrResponder::new)?;
 
     Ok(Json(todos))
 }
 
-#[get("/<id>")]
-pub async fn get_task_by_id(id: Guid, service: &State<TodoService>) -> ActionResult<Todo> {
-    let agg = service.get_task_by_id(id).await.map_err(TodoErrResponder::new)?;
-    let todo = Todo::from_agg(agg);
+/// Without query parameters this reads the current todo. `at_version` or an RFC3339 `as_of`
+/// timestamp return the todo as it was at that point instead, and 404 before it was created
+/// or for a version it has not reached.
+#[get("/<id>?<at_version>&<as_of>")]
+pub async fn get_task_by_id(id: Guid, at_version: Option<u32>, as_of: Option<&str>, service: &State<TodoService>) -> ActionResult<Todo> {
+    let at = match (at_version, as_of) {
+        (None, None) => { None }
+        (Some(version), None) => { Some(PointInTime::Version(version)) }
+        (None, Some(as_of)) => { Some(PointInTime::Timestamp(parse_timestamp("as_of", as_of)?)) }
+        (Some(_), Some(_)) => {
+            return Err(TodoErrResponder::new(TodoServiceErr::Validation("Use either at_version or as_of, not both".to_string())));
+        }
+    };
+
+    let todo = match at {
+        None => { service.get_task_by_id(id).await.map_err(TodoErrResponder::new)? }
+        Some(at) => { service.todo(service.get_task_at(id, at).await.map_err(TodoErrResponder::new)?) }
+    };
 
     Ok(Json(todo))
 }
 
-#[patch("/<id>", format = "json", data = "<event>")]
-pub async fn update_task(id: Guid, event: Json<TodoEvent>, service: &State<TodoService>) -> ActionResult<Todo> {
-    let agg = service.update_task(id, event.into_inner()).await.map_err(TodoErrResponder::new)?;
-    let todo = Todo::from_agg(agg);
+/// Pushes every newly persisted event with the resulting todo as server-sent events. Each
+/// message id is the event's position in the global log, so clients resume through
+/// `Last-Event-ID`, also across restarts.
+#[get("/stream")]
+pub async fn stream_changes(last_event_id: LastEventId, service: &State<TodoService>, mut shutdown: Shutdown) -> Result<EventStream![Event + '_], TodoErrResponder> {
+    // Subscribe before reading the start position so nothing is published in between.
+    let mut receiver = service.subscribe();
+    let start = match last_event_id.0 {
+        Some(position) => { position }
+        None => { service.last_position().await.map_err(TodoErrResponder::new)? }
+    };
+
+    Ok(EventStream! {
+        let mut last_position = start;
+        loop {
+            // Catch up from the event store until it has nothing newer.
+            loop {
+                let changes = match service.changes_after(last_position, DEFAULT_BATCH_SIZE).await {
+                    Ok(changes) => { changes }
+                    Err(err) => {
+                        log::warn!("Could not read changes after position {}: {}", last_position, err);
+                        return;
+                    }
+                };
+                if changes.is_empty() {
+                    break;
+                }
+                for change in changes {
+                    last_position = change.position;
+                    yield Event::json(&change).id(change.position.to_string());
+                }
+            }
+
+            loop {
+                let change = select! {
+                    received = receiver.recv() => match received {
+                        Ok(change) => { change }
+                        // Missed changes are read back from the event store.
+                        Err(RecvError::Lagged(_)) => { break; }
+                        Err(RecvError::Closed) => { return; }
+                    },
+                    _ = &mut shutdown => { return; }
+                };
+                if change.position <= last_position {
+                    continue;
+                }
+                if change.position != last_position + 1 {
+                    // Another append is still ahead of this one, catch up in order instead.
+                    break;
+                }
+
+                last_position = change.position;
+                yield Event::json(&change).id(change.position.to_string());
+            }
+        }
+    })
+}
+
+/// One batch of the global event log. Pass `checkpoint` as `after` to read the next batch.
+#[derive(Serialize)]
+pub struct EventLogBatch {
+    events: Vec<RecordedEvent<Value>>,
+    checkpoint: u64,
+}
+
+/// Reads the events of every stream in position order, starting after position `after`.
+#[get("/events?<after>&<limit>")]
+pub async fn read_event_log(after: Option<u64>, limit: Option<usize>, service: &State<TodoService>) -> ActionResult<EventLogBatch> {
+    let after = after.unwrap_or(0);
+    let events = service.read_all(after, limit).await.map_err(TodoErrResponder::new)?;
+    let checkpoint = events.last().map_or(after, |event| event.position);
+
+    Ok(Json(EventLogBatch { events, checkpoint }))
+}
+
+/// Deleted todos that have not been purged yet. They come back with a `RestoreTodo` command.
+#[get("/trash")]
+pub async fn list_trash(service: &State<TodoService>) -> ActionResult<Vec<Todo>> {
+    let trash = service.trash().await.map_err(TodoErrResponder::new)?;
+
+    Ok(Json(trash))
+}
+
+#[get("/<id>/tree")]
+pub async fn get_task_tree(id: Guid, service: &State<TodoService>) -> ActionResult<TodoTree> {
+    let tree = service.get_task_tree(id).await.map_err(TodoErrResponder::new)?;
+
+    Ok(Json(tree))
+}
+
+#[get("/<id>/events?<from_version>&<to_version>")]
+pub async fn get_task_history(id: Guid, from_version: Option<u32>, to_version: Option<u32>, service: &State<TodoService>) -> ActionResult<Vec<TodoHistoryEntry>> {
+    let history = service
+        .get_history(id, from_version, to_version).await
+        .map_err(TodoErrResponder::new)?
+        .into_iter()
+        .map(|envelope| TodoHistoryEntry {
+            version: envelope.version(),
+            event_id: envelope.event_id,
+            recorded_at: envelope.recorded_at,
+            actor: envelope.actor,
+            correlation_id: envelope.correlation_id,
+            causation_id: envelope.causation_id,
+            event: envelope.event,
+        })
+        .collect();
+
+    Ok(Json(history))
+}
+
+/// Runs a `TodoCommand` such as `{"type": "RenameTodo", "name": "..."}` against the todo.
+/// `expected_version` rejects the command with 409 if the todo changed in the meantime.
+/// A cascading `CompleteTodo` checks every open subtask first but is not atomic: if it
+/// still fails part of the way, the 409 lists the subtasks it did complete.
+#[patch("/<id>?<expected_version>", format = "json", data = "<command>")]
+pub async fn update_task(id: Guid, expected_version: Option<u32>, command: Json<TodoCommand>, metadata: EventMetadata, service: &State<TodoService>) -> ActionResult<Todo> {
+    let agg = service.update_task(id, command.into_inner(), expected_version, &metadata).await.map_err(TodoErrResponder::new)?;
+    let todo = service.todo(agg);
     
     Ok(Json(todo))
 }
 
 #[post("/", format = "json", data = "<name>")]
-pub async fn create_task(name: Json<CreateTodoRequest>, service: &State<TodoService>) -> ActionResult<Todo> {
-    let agg = service.create_task(name.into_inner().name).await.map_err(TodoErrResponder::new)?;
-    let todo = Todo::from_agg(agg);
+pub async fn create_task(name: Json<CreateTodoRequest>, metadata: EventMetadata, service: &State<TodoService>) -> ActionResult<Todo> {
+    let agg = service.create_task(name.into_inner().name, &metadata).await.map_err(TodoErrResponder::new)?;
+    let todo = service.todo(agg);
 
     Ok(Json(todo))
 }
 
+#[derive(Serialize)]
+pub struct RebuildResponse {
+    projected: usize,
+}
+
+/// Regenerates the `todo` read model from the event store.
+#[post("/projection/rebuild")]
+pub async fn rebuild_projection(service: &State<TodoService>) -> ActionResult<RebuildResponse> {
+    let projected = service.rebuild_projection().await.map_err(TodoErrResponder::new)?;
+
+    Ok(Json(RebuildResponse { projected }))
+}
+
+/// Processed position and lag of every background subscriber.
+#[get("/subscriptions")]
+pub async fn subscription_status(subscriptions: &State<Subscriptions>) -> ActionResult<Vec<SubscriptionStatus>> {
+    let status = subscriptions
+        .status().await
+        .map_err(|err| TodoErrResponder::new(err.into()))?;
+
+    Ok(Json(status))
+}
+
 #[async_trait]
 pub trait AddTodo {
-    async fn add_todo(self, mongodb: &Client) -> Rocket<Build>;
+    async fn add_todo(self, todo_repo: Box<dyn TodoRepository>, event_repo: Box<dyn EventStore>, snapshot_interval: u32, subscribers: Vec<Subscriber>, subscription_settings: SubscriptionSettings, trash_settings: TrashSettings) -> Rocket<Build>;
 }
 
 #[async_trait]
 impl AddTodo for Rocket<Build> {
-    async fn add_todo(self, mongodb: &Client) -> Rocket<Build> {
-        let todo_repo = MongoTodoRepository::new(mongodb);
-        let event_repo = TodoEventCollRepo::new(mongodb);
+    async fn add_todo(self, todo_repo: Box<dyn TodoRepository>, event_repo: Box<dyn EventStore>, snapshot_interval: u32, subscribers: Vec<Subscriber>, subscription_settings: SubscriptionSettings, trash_settings: TrashSettings) -> Rocket<Build> {
         let todo_service = TodoService::init(
-            Box::new(todo_repo),
+            todo_repo,
             event_repo,
+            snapshot_interval,
+            Box::new(SystemClock),
         ).await;
+        let mut subscribers = subscribers;
+        subscribers.push(Subscriber::new(PROJECTION_SUBSCRIBER, Box::new(todo_service.projection())));
+        let subscriptions = Subscriptions::new(todo_service.event_store(), subscribers, subscription_settings);
 
         self
             .manage(todo_service)
+            .manage(subscriptions)
+            .attach(AdHoc::on_liftoff("Todo subscriptions", |rocket| Box::pin(async move {
+                if let (Some(service), Some(subscriptions)) = (rocket.state::<TodoService>(), rocket.state::<Subscriptions>()) {
+                    subscriptions.start(|| service.subscribe(), rocket.shutdown());
+                }
+            })))
+            .attach(AdHoc::on_liftoff("Todo trash purge", move |rocket| Box::pin(async move {
+                if let Some(service) = rocket.state::<TodoService>() {
+                    service.purger(trash_settings, Box::new(SystemClock)).start(rocket.shutdown());
+                }
+            })))
             .mount("/api/todo", routes![
                 create_task,
                 update_task,
                 list_tasks,
-                get_task_by_id
+                stream_changes,
+                read_event_log,
+                subscription_status,
+                get_task_by_id,
+                get_task_history,
+                get_task_tree,
+                list_trash,
+                rebuild_projection
             ])
             .register("/api/todo", catchers![
                 catch_malformed_request
             ])
+            .mount("/api/lists", routes![
+                list::list_lists,
+                list::get_list,
+                list::create_list,
+                list::update_list,
+                list::list_todos,
+                list::move_todo,
+                list::remove_todo
+            ])
+            .register("/api/lists", catchers![
+                catch_malformed_request
+            ])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rocket::http::Status as HttpStatus;
+    use crate::guid::Guid;
+    use crate::routes::todo::TodoErrResponder;
+    use crate::services::todo::TodoServiceErr;
+
+    #[test]
+    fn conflicts_are_reported_as_409() {
+        let conflict = TodoErrResponder::new(TodoServiceErr::Conflict("Todo is at version 2, not 1".to_string()));
+        assert_eq!(conflict.status(), HttpStatus::Conflict);
+        assert_eq!(conflict.err.code(), "conflict");
+    }
+
+    #[test]
+    fn partially_applied_cascades_are_reported_as_409() {
+        let partial = TodoErrResponder::new(TodoServiceErr::PartiallyApplied { message: "Completed 1 subtasks before failing".to_string(), completed: vec![Guid::new()] });
+        assert_eq!(partial.status(), HttpStatus::Conflict);
+        assert_eq!(partial.err.code(), "partially_applied");
     }
 }
@@ -1,15 +1,25 @@
 use std::fmt::{Debug, Display, Formatter};
+use chrono::{DateTime, Utc};
+use serde::de::DeserializeOwned;
 use serde_derive::{Deserialize, Serialize};
 use crate::guid::Guid;
 use crate::routes::todo::Status;
 
-#[derive(Debug, Deserialize, Serialize, Clone)]
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
 #[serde(tag = "type")]
 pub enum TodoEvent {
     Create { name: String, id: Guid },
     ChangeName { new_name: String, version: u32 },
     ChangeStatus { status: Status, version: u32 },
+    SetDueDate { due: DateTime<Utc>, version: u32 },
+    ClearDueDate { version: u32 },
+    /// The todo moved to `list`, or out of any list when it is `None`.
+    ChangeList { list: Option<Guid>, version: u32 },
+    AttachToParent { parent: Guid, version: u32 },
+    DetachFromParent { version: u32 },
     Delete { version: u32 },
+    /// Brings a deleted todo back as it was before `Delete`.
+    Restore { version: u32 },
 }
 
 impl TodoEvent {
@@ -18,14 +28,32 @@ impl TodoEvent {
             TodoEvent::Create { .. } => { 1 }
             TodoEvent::ChangeName { version, .. } => { version.to_owned() }
             TodoEvent::ChangeStatus { version, .. } => { version.to_owned() }
+            TodoEvent::SetDueDate { version, .. } => { version.to_owned() }
+            TodoEvent::ClearDueDate { version } => { version.to_owned() }
+            TodoEvent::ChangeList { version, .. } => { version.to_owned() }
+            TodoEvent::AttachToParent { version, .. } => { version.to_owned() }
+            TodoEvent::DetachFromParent { version } => { version.to_owned() }
             TodoEvent::Delete { version, .. } => { version.to_owned() }
+            TodoEvent::Restore { version } => { version.to_owned() }
         }
     }
 }
 
+impl DomainEvent for TodoEvent {
+    fn version(&self) -> u32 {
+        TodoEvent::version(self)
+    }
+}
+
+#[derive(PartialEq)]
 pub enum AggregateErr {
     ConcurrencyErr,
-
+    /// The aggregate was deleted and accepts no further events.
+    Deleted,
+    /// `STATUS_TRANSITIONS` does not allow moving from `from` to `to`.
+    InvalidTransition { from: Status, to: Status },
+    /// Only deleted aggregates can be restored.
+    NotDeleted,
 }
 
 impl Debug for AggregateErr {
@@ -34,6 +62,15 @@ impl Debug for AggregateErr {
             AggregateErr::ConcurrencyErr => {
                 f.write_str("Attempted to apply multiple updates of the same version")
             }
+            AggregateErr::Deleted => {
+                f.write_str("Attempted to update a deleted aggregate")
+            }
+            AggregateErr::InvalidTransition { from, to } => {
+                write!(f, "Attempted to change status from {:?} to {:?}", from, to)
+            }
+            AggregateErr::NotDeleted => {
+                f.write_str("Attempted to restore an aggregate that is not deleted")
+            }
         }
     }
 }
@@ -44,6 +81,117 @@ impl Display for AggregateErr {
             AggregateErr::ConcurrencyErr => {
                 f.write_str("Attempted to apply multiple updates of the same version")
             }
+            AggregateErr::Deleted => {
+                f.write_str("Attempted to update a deleted aggregate")
+            }
+            AggregateErr::InvalidTransition { from, to } => {
+                write!(f, "A todo cannot go from {:?} to {:?}", from, to)
+            }
+            AggregateErr::NotDeleted => {
+                f.write_str("Attempted to restore an aggregate that is not deleted")
+            }
+        }
+    }
+}
+
+/// Every status change a todo allows. A blocked todo has to be unblocked before it is
+/// completed, and a cancelled one can only be reopened.
+const STATUS_TRANSITIONS: &[(Status, Status)] = &[
+    (Status::Incomplete, Status::InProgress),
+    (Status::Incomplete, Status::Blocked),
+    (Status::Incomplete, Status::Complete),
+    (Status::Incomplete, Status::Cancelled),
+    (Status::InProgress, Status::Incomplete),
+    (Status::InProgress, Status::Blocked),
+    (Status::InProgress, Status::Complete),
+    (Status::InProgress, Status::Cancelled),
+    (Status::Blocked, Status::Incomplete),
+    (Status::Blocked, Status::InProgress),
+    (Status::Blocked, Status::Cancelled),
+    (Status::Complete, Status::Incomplete),
+    (Status::Cancelled, Status::Incomplete),
+];
+
+pub fn can_change_status(from: &Status, to: &Status) -> bool {
+    STATUS_TRANSITIONS.iter().any(|(allowed_from, allowed_to)| allowed_from == from && allowed_to == to)
+}
+
+/// What a client asks a todo to do. `decide` turns a command into the events that record it,
+/// so clients never write events themselves.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+#[serde(tag = "type")]
+#[allow(clippy::enum_variant_names)]
+pub enum TodoCommand {
+    /// Issued by the service when a todo is created, never accepted from clients.
+    #[serde(skip_deserializing)]
+    CreateTodo { id: Guid, name: String },
+    RenameTodo { name: String },
+    /// A todo with open subtasks is only completed with `cascade`, which completes them too.
+    CompleteTodo {
+        #[serde(default)]
+        cascade: bool,
+    },
+    /// Back to `Incomplete`, the only way out of `Cancelled`.
+    ReopenTodo,
+    StartTodo,
+    BlockTodo,
+    CancelTodo,
+    SetDueDate { due: DateTime<Utc> },
+    ClearDueDate,
+    /// Issued by the service while it moves a todo between lists, so that the lists record
+    /// the move as well.
+    #[serde(skip_deserializing)]
+    MoveTodo { list: Option<Guid> },
+    /// Makes the todo a subtask of `parent`. The service checks that this creates no cycle.
+    AttachToParent { parent: Guid },
+    DetachFromParent,
+    DeleteTodo,
+    /// Undoes `DeleteTodo` until the todo is purged from the trash.
+    RestoreTodo,
+}
+
+/// A command that the current state of an aggregate does not allow.
+#[derive(Debug, Clone, PartialEq)]
+pub enum DomainError {
+    AlreadyExists,
+    Deleted,
+    EmptyName,
+    NameUnchanged,
+    AlreadyComplete,
+    AlreadyIncomplete,
+    DueDateUnchanged,
+    NoDueDate,
+    Archived,
+    AlreadyArchived,
+    AlreadyInList,
+    NotInList,
+    ParentUnchanged,
+    NoParent,
+    Cycle,
+    OpenSubtasks,
+    NotDeleted,
+}
+
+impl Display for DomainError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DomainError::AlreadyExists => { f.write_str("Already exists") }
+            DomainError::Deleted => { f.write_str("Todo was deleted") }
+            DomainError::EmptyName => { f.write_str("Name must not be empty") }
+            DomainError::NameUnchanged => { f.write_str("Name is unchanged") }
+            DomainError::AlreadyComplete => { f.write_str("Todo is already complete") }
+            DomainError::AlreadyIncomplete => { f.write_str("Todo is not complete") }
+            DomainError::DueDateUnchanged => { f.write_str("Todo is already due at this time") }
+            DomainError::NoDueDate => { f.write_str("Todo has no due date") }
+            DomainError::Archived => { f.write_str("List is archived") }
+            DomainError::AlreadyArchived => { f.write_str("List is already archived") }
+            DomainError::AlreadyInList => { f.write_str("Todo is already in this list") }
+            DomainError::NotInList => { f.write_str("Todo is not in this list") }
+            DomainError::ParentUnchanged => { f.write_str("Todo is already a subtask of this parent") }
+            DomainError::NoParent => { f.write_str("Todo has no parent") }
+            DomainError::Cycle => { f.write_str("A todo cannot be a subtask of itself or of its own subtasks") }
+            DomainError::OpenSubtasks => { f.write_str("Todo has open subtasks; complete them first or ask to cascade") }
+            DomainError::NotDeleted => { f.write_str("Todo is not deleted") }
         }
     }
 }
@@ -53,26 +201,55 @@ pub struct ValidTodoEvent {
     event: TodoEvent,
 }
 
-impl ValidTodoEvent {
-    pub fn event(&self) -> TodoEvent {
-        self.event.clone()
-    }    
+impl From<ValidTodoEvent> for TodoEvent {
+    fn from(valid_event: ValidTodoEvent) -> TodoEvent {
+        valid_event.event
+    }
 }
 
-pub trait Aggregate {
-    type Event;
-    type ValidEvent;
+/// An event an `Aggregate` is folded from. Event stores keep events as plain serialized data,
+/// so they never need to know the concrete type.
+pub trait DomainEvent: serde::Serialize + DeserializeOwned + Clone + Send + Sync + 'static {
+    /// Version of the aggregate right after this event was applied.
     fn version(&self) -> u32;
+}
+
+pub trait Aggregate: serde::Serialize + DeserializeOwned + Clone + Send + Sync + 'static {
+    /// Names the aggregate type. Every aggregate is stored as its own stream named
+    /// `<TYPE>-<id>`, so aggregates of different types can share one event store.
+    const TYPE: &'static str;
+    type Event: DomainEvent;
+    type ValidEvent: Into<Self::Event>;
+    type Command;
+
+    fn stream_name(id: Guid) -> String {
+        format!("{}-{}", Self::TYPE, id)
+    }
+
+    fn version(&self) -> u32;
+    /// Checks `command` against the business rules and returns the events that carry it out.
+    /// Nothing is applied; the events still go through `try_apply` when they are stored.
+    fn decide(&self, command: Self::Command) -> Result<Vec<Self::Event>, DomainError>;
     fn try_apply(&self, event: Self::Event) -> Result<Self::ValidEvent, AggregateErr>;
     fn apply(self, event: &Self::ValidEvent) -> Self;
     fn from_events(events: Vec<Self::Event>) -> Self;
+    /// Continues folding from previously stored state, so only the events recorded after
+    /// that state was captured have to be replayed.
+    fn from_snapshot(state: Self, events: Vec<Self::Event>) -> Self;
 }
 
-#[derive(Debug)]
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
 pub struct TodoAggregate {
     pub id: Guid,
     pub status: Status,
     pub name: String,
+    /// Snapshots taken before due dates existed have no `due`.
+    #[serde(default)]
+    pub due: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub list: Option<Guid>,
+    #[serde(default)]
+    pub parent: Option<Guid>,
     version: u32,
     pub is_deleted: bool,
 }
@@ -83,28 +260,138 @@ impl TodoAggregate {
             id: Guid::empty(),
             status: Status::Incomplete,
             name: "".to_string(),
+            due: None,
+            list: None,
+            parent: None,
             version: 0,
             is_deleted: false
         }
     }
+
+    /// Checks that `event` continues the stream, without looking at what it changes.
+    fn follows(&self, event: TodoEvent) -> Result<ValidTodoEvent, AggregateErr> {
+        let restore = matches!(event, TodoEvent::Restore { .. });
+        if self.is_deleted && !restore {
+            Err(AggregateErr::Deleted)
+        } else if !self.is_deleted && restore {
+            Err(AggregateErr::NotDeleted)
+        } else if event.version() != self.version + 1 {
+            Err(AggregateErr::ConcurrencyErr)
+        } else if self.version == 0 && !matches!(event, TodoEvent::Create { .. }) {
+            // Only `Create` starts a stream; anything else at version 1 was meant for a todo
+            // that does not exist.
+            Err(AggregateErr::ConcurrencyErr)
+        } else {
+            Ok(ValidTodoEvent { event })
+        }
+    }
 }
 
 impl Aggregate for TodoAggregate {
+    const TYPE: &'static str = "todo";
     type Event = TodoEvent;
     type ValidEvent = ValidTodoEvent;
+    type Command = TodoCommand;
 
     fn version(&self) -> u32 {
         self.version
     }
 
+    fn decide(&self, command: TodoCommand) -> Result<Vec<TodoEvent>, DomainError> {
+        if self.is_deleted && command != TodoCommand::RestoreTodo {
+            return Err(DomainError::Deleted);
+        }
+        let version = self.version + 1;
+
+        let event = match command {
+            TodoCommand::CreateTodo { id, name } => {
+                if self.version > 0 {
+                    return Err(DomainError::AlreadyExists);
+                }
+                if name.trim().is_empty() {
+                    return Err(DomainError::EmptyName);
+                }
+                TodoEvent::Create { name, id }
+            }
+            TodoCommand::RenameTodo { name } => {
+                if name.trim().is_empty() {
+                    return Err(DomainError::EmptyName);
+                }
+                if name == self.name {
+                    return Err(DomainError::NameUnchanged);
+                }
+                TodoEvent::ChangeName { new_name: name, version }
+            }
+            TodoCommand::CompleteTodo { .. } => {
+                if self.status == Status::Complete {
+                    return Err(DomainError::AlreadyComplete);
+                }
+                TodoEvent::ChangeStatus { status: Status::Complete, version }
+            }
+            TodoCommand::ReopenTodo => {
+                if self.status == Status::Incomplete {
+                    return Err(DomainError::AlreadyIncomplete);
+                }
+                TodoEvent::ChangeStatus { status: Status::Incomplete, version }
+            }
+            // Whether the todo may move to the new status is up to `STATUS_TRANSITIONS`.
+            TodoCommand::StartTodo => { TodoEvent::ChangeStatus { status: Status::InProgress, version } }
+            TodoCommand::BlockTodo => { TodoEvent::ChangeStatus { status: Status::Blocked, version } }
+            TodoCommand::CancelTodo => { TodoEvent::ChangeStatus { status: Status::Cancelled, version } }
+            TodoCommand::SetDueDate { due } => {
+                if self.due == Some(due) {
+                    return Err(DomainError::DueDateUnchanged);
+                }
+                TodoEvent::SetDueDate { due, version }
+            }
+            TodoCommand::ClearDueDate => {
+                if self.due.is_none() {
+                    return Err(DomainError::NoDueDate);
+                }
+                TodoEvent::ClearDueDate { version }
+            }
+            TodoCommand::MoveTodo { list } => {
+                if self.list == list {
+                    return Err(if list.is_some() { DomainError::AlreadyInList } else { DomainError::NotInList });
+                }
+                TodoEvent::ChangeList { list, version }
+            }
+            TodoCommand::AttachToParent { parent } => {
+                if parent == self.id {
+                    return Err(DomainError::Cycle);
+                }
+                if self.parent == Some(parent) {
+                    return Err(DomainError::ParentUnchanged);
+                }
+                TodoEvent::AttachToParent { parent, version }
+            }
+            TodoCommand::DetachFromParent => {
+                if self.parent.is_none() {
+                    return Err(DomainError::NoParent);
+                }
+                TodoEvent::DetachFromParent { version }
+            }
+            TodoCommand::DeleteTodo => { TodoEvent::Delete { version } }
+            TodoCommand::RestoreTodo => {
+                if !self.is_deleted {
+                    return Err(DomainError::NotDeleted);
+                }
+                TodoEvent::Restore { version }
+            }
+        };
+
+        Ok(vec![event])
+    }
+
     fn try_apply(&self, event: Self::Event) -> Result<ValidTodoEvent, AggregateErr> {
-        if self.is_deleted {
-            Err(AggregateErr::ConcurrencyErr)
-        } else if event.version() != self.version + 1 {
-            Err(AggregateErr::ConcurrencyErr)
-        } else {
-            Ok(ValidTodoEvent { event })
+        let valid_event = self.follows(event)?;
+        if let TodoEvent::ChangeStatus { status, .. } = &valid_event.event {
+            if !can_change_status(&self.status, status) {
+                return Err(AggregateErr::InvalidTransition { from: self.status.clone(), to: status.clone() });
+            }
         }
+
+        Ok(valid_event)
     }
 
     fn apply(mut self, valid_event: &ValidTodoEvent) -> TodoAggregate {
@@ -113,7 +400,7 @@ impl Aggregate for TodoAggregate {
         match event {
             TodoEvent::Create { name, id } => {
                 self.name = name.clone();
-                self.id = id.clone();
+                self.id = *id;
             }
             TodoEvent::ChangeName { new_name, .. } => {
                 self.name = new_name.clone();
@@ -121,19 +408,356 @@ impl Aggregate for TodoAggregate {
             TodoEvent::ChangeStatus { status, .. } => {
                 self.status = status.clone();
             }
+            TodoEvent::SetDueDate { due, .. } => {
+                self.due = Some(*due);
+            }
+            TodoEvent::ClearDueDate { .. } => {
+                self.due = None;
+            }
+            TodoEvent::ChangeList { list, .. } => {
+                self.list = *list;
+            }
+            TodoEvent::AttachToParent { parent, .. } => {
+                self.parent = Some(*parent);
+            }
+            TodoEvent::DetachFromParent { .. } => {
+                self.parent = None;
+            }
             TodoEvent::Delete { .. } => { self.is_deleted = true; }
+            TodoEvent::Restore { .. } => { self.is_deleted = false; }
         };
 
         self
     }
 
     fn from_events(events: Vec<Self::Event>) -> TodoAggregate {
-        let mut agg = TodoAggregate::new();
-        agg = events.into_iter().fold(agg, |agg, e| {
-            let valid_event = agg.try_apply(e).unwrap();
+        TodoAggregate::from_snapshot(TodoAggregate::new(), events)
+    }
+
+    fn from_snapshot(state: TodoAggregate, events: Vec<Self::Event>) -> TodoAggregate {
+        // Stored events are replayed as recorded. The transition table only guards new
+        // events, so streams written before it existed keep loading.
+        events.into_iter().fold(state, |agg, e| {
+            let valid_event = agg.follows(e).unwrap();
             agg.apply(&valid_event)
-        });
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{TimeZone, Utc};
+    use crate::guid::Guid;
+    use crate::routes::todo::Status;
+    use crate::services::aggregate::{Aggregate, AggregateErr, DomainError, TodoAggregate, TodoCommand, TodoEvent};
+    use crate::services::scenario::Scenario;
+
+    fn history() -> Vec<TodoEvent> {
+        let mut events = vec![TodoEvent::Create { name: "first".to_string(), id: Guid::new() }];
+        for version in 2..=30 {
+            events.push(match version % 3 {
+                0 => { TodoEvent::ChangeStatus { status: Status::Complete, version } }
+                1 => { TodoEvent::ChangeStatus { status: Status::Incomplete, version } }
+                _ => { TodoEvent::ChangeName { new_name: format!("name {version}"), version } }
+            });
+        }
+
+        events
+    }
+
+    #[test]
+    fn from_snapshot_matches_full_replay() {
+        let events = history();
+        let full_replay = TodoAggregate::from_events(events.clone());
+
+        for split in 0..=events.len() {
+            let snapshot = TodoAggregate::from_events(events[..split].to_vec());
+            let resumed = TodoAggregate::from_snapshot(snapshot, events[split..].to_vec());
 
-        agg
+            assert_eq!(resumed, full_replay);
+        }
+    }
+
+    fn created(id: Guid) -> TodoEvent {
+        TodoEvent::Create { name: "milk".to_string(), id }
+    }
+
+    fn renamed(name: &str, version: u32) -> TodoEvent {
+        TodoEvent::ChangeName { new_name: name.to_string(), version }
     }
-}
\ No newline at end of file
+
+    fn status(status: Status, version: u32) -> TodoEvent {
+        TodoEvent::ChangeStatus { status, version }
+    }
+
+    #[test]
+    fn create_starts_a_todo_at_version_one() {
+        let id = Guid::new();
+        let todo = Scenario::<TodoAggregate>::given_nothing()
+            .when_event(created(id))
+            .then_events(vec![created(id)]);
+
+        assert_eq!((todo.id, todo.name.as_str(), todo.version()), (id, "milk", 1));
+        assert_eq!(todo.status, Status::Incomplete);
+    }
+
+    #[test]
+    fn create_cannot_be_applied_twice() {
+        let id = Guid::new();
+        Scenario::<TodoAggregate>::given(vec![created(id)])
+            .when_event(created(id))
+            .then_error(AggregateErr::ConcurrencyErr);
+    }
+
+    #[test]
+    fn change_name_renames_at_the_next_version() {
+        let todo = Scenario::<TodoAggregate>::given(vec![created(Guid::new())])
+            .when_event(renamed("oat milk", 2))
+            .then_events(vec![renamed("oat milk", 2)]);
+
+        assert_eq!((todo.name.as_str(), todo.version()), ("oat milk", 2));
+    }
+
+    #[test]
+    fn change_status_completes_and_reopens() {
+        let todo = Scenario::<TodoAggregate>::given(vec![created(Guid::new()), status(Status::Complete, 2)])
+            .when_event(status(Status::Incomplete, 3))
+            .then_events(vec![status(Status::Incomplete, 3)]);
+
+        assert_eq!(todo.status, Status::Incomplete);
+    }
+
+    #[test]
+    fn delete_marks_the_todo_deleted() {
+        let todo = Scenario::<TodoAggregate>::given(vec![created(Guid::new())])
+            .when_event(TodoEvent::Delete { version: 2 })
+            .then_events(vec![TodoEvent::Delete { version: 2 }]);
+
+        assert!(todo.is_deleted);
+    }
+
+    #[test]
+    fn events_must_carry_the_next_version() {
+        let given = vec![created(Guid::new()), renamed("oat milk", 2)];
+        for version in [1, 2, 4, 10] {
+            Scenario::<TodoAggregate>::given(given.clone())
+                .when_event(renamed("soy milk", version))
+                .then_error(AggregateErr::ConcurrencyErr);
+        }
+        Scenario::<TodoAggregate>::given(given.clone())
+            .when_event(TodoEvent::Delete { version: 2 })
+            .then_error(AggregateErr::ConcurrencyErr);
+        Scenario::<TodoAggregate>::given_nothing()
+            .when_event(renamed("soy milk", 1))
+            .then_error(AggregateErr::ConcurrencyErr);
+    }
+
+    #[test]
+    fn only_restore_is_accepted_after_delete() {
+        let given = vec![created(Guid::new()), TodoEvent::Delete { version: 2 }];
+        for event in [renamed("oat milk", 3), status(Status::Complete, 3), TodoEvent::Delete { version: 3 }] {
+            Scenario::<TodoAggregate>::given(given.clone())
+                .when_event(event)
+                .then_error(AggregateErr::Deleted);
+        }
+        Scenario::<TodoAggregate>::given(given)
+            .when(TodoCommand::RenameTodo { name: "oat milk".to_string() })
+            .then_rejected(DomainError::Deleted);
+    }
+
+    #[test]
+    fn create_command_requires_a_new_todo_with_a_name() {
+        let id = Guid::new();
+        Scenario::<TodoAggregate>::given_nothing()
+            .when(TodoCommand::CreateTodo { id, name: "milk".to_string() })
+            .then_events(vec![created(id)]);
+        Scenario::<TodoAggregate>::given_nothing()
+            .when(TodoCommand::CreateTodo { id, name: " ".to_string() })
+            .then_rejected(DomainError::EmptyName);
+        Scenario::<TodoAggregate>::given(vec![created(id)])
+            .when(TodoCommand::CreateTodo { id, name: "again".to_string() })
+            .then_rejected(DomainError::AlreadyExists);
+    }
+
+    #[test]
+    fn rename_command_requires_a_different_name() {
+        let given = vec![created(Guid::new())];
+        Scenario::<TodoAggregate>::given(given.clone())
+            .when(TodoCommand::RenameTodo { name: "oat milk".to_string() })
+            .then_events(vec![renamed("oat milk", 2)]);
+        Scenario::<TodoAggregate>::given(given.clone())
+            .when(TodoCommand::RenameTodo { name: "milk".to_string() })
+            .then_rejected(DomainError::NameUnchanged);
+        Scenario::<TodoAggregate>::given(given)
+            .when(TodoCommand::RenameTodo { name: "".to_string() })
+            .then_rejected(DomainError::EmptyName);
+    }
+
+    #[test]
+    fn complete_and_reopen_commands_require_the_opposite_status() {
+        let open = vec![created(Guid::new())];
+        let mut complete = open.clone();
+        complete.push(status(Status::Complete, 2));
+
+        Scenario::<TodoAggregate>::given(open.clone())
+            .when(TodoCommand::CompleteTodo { cascade: false })
+            .then_events(vec![status(Status::Complete, 2)]);
+        Scenario::<TodoAggregate>::given(open)
+            .when(TodoCommand::ReopenTodo)
+            .then_rejected(DomainError::AlreadyIncomplete);
+        Scenario::<TodoAggregate>::given(complete.clone())
+            .when(TodoCommand::ReopenTodo)
+            .then_events(vec![status(Status::Incomplete, 3)]);
+        Scenario::<TodoAggregate>::given(complete)
+            .when(TodoCommand::CompleteTodo { cascade: false })
+            .then_rejected(DomainError::AlreadyComplete);
+    }
+
+    #[test]
+    fn due_date_commands_set_and_clear_the_due_date() {
+        let due = Utc.with_ymd_and_hms(2024, 5, 1, 12, 0, 0).unwrap();
+        let open = vec![created(Guid::new())];
+        let mut scheduled = open.clone();
+        scheduled.push(TodoEvent::SetDueDate { due, version: 2 });
+
+        let todo = Scenario::<TodoAggregate>::given(open.clone())
+            .when(TodoCommand::SetDueDate { due })
+            .then_events(vec![TodoEvent::SetDueDate { due, version: 2 }]);
+        assert_eq!(todo.due, Some(due));
+        Scenario::<TodoAggregate>::given(open)
+            .when(TodoCommand::ClearDueDate)
+            .then_rejected(DomainError::NoDueDate);
+        Scenario::<TodoAggregate>::given(scheduled.clone())
+            .when(TodoCommand::SetDueDate { due })
+            .then_rejected(DomainError::DueDateUnchanged);
+        let todo = Scenario::<TodoAggregate>::given(scheduled)
+            .when(TodoCommand::ClearDueDate)
+            .then_events(vec![TodoEvent::ClearDueDate { version: 3 }]);
+        assert_eq!(todo.due, None);
+    }
+
+    #[test]
+    fn move_command_changes_the_list() {
+        let list = Guid::new();
+        let unlisted = vec![created(Guid::new())];
+        let mut listed = unlisted.clone();
+        listed.push(TodoEvent::ChangeList { list: Some(list), version: 2 });
+
+        let todo = Scenario::<TodoAggregate>::given(unlisted.clone())
+            .when(TodoCommand::MoveTodo { list: Some(list) })
+            .then_events(vec![TodoEvent::ChangeList { list: Some(list), version: 2 }]);
+        assert_eq!(todo.list, Some(list));
+        Scenario::<TodoAggregate>::given(unlisted)
+            .when(TodoCommand::MoveTodo { list: None })
+            .then_rejected(DomainError::NotInList);
+        Scenario::<TodoAggregate>::given(listed.clone())
+            .when(TodoCommand::MoveTodo { list: Some(list) })
+            .then_rejected(DomainError::AlreadyInList);
+        Scenario::<TodoAggregate>::given(listed)
+            .when(TodoCommand::MoveTodo { list: None })
+            .then_events(vec![TodoEvent::ChangeList { list: None, version: 3 }]);
+    }
+
+    #[test]
+    fn parent_commands_attach_and_detach() {
+        let (id, parent) = (Guid::new(), Guid::new());
+        let mut attached = vec![created(id)];
+        attached.push(TodoEvent::AttachToParent { parent, version: 2 });
+
+        let todo = Scenario::<TodoAggregate>::given(vec![created(id)])
+            .when(TodoCommand::AttachToParent { parent })
+            .then_events(vec![TodoEvent::AttachToParent { parent, version: 2 }]);
+        assert_eq!(todo.parent, Some(parent));
+        Scenario::<TodoAggregate>::given(vec![created(id)])
+            .when(TodoCommand::AttachToParent { parent: id })
+            .then_rejected(DomainError::Cycle);
+        Scenario::<TodoAggregate>::given(vec![created(id)])
+            .when(TodoCommand::DetachFromParent)
+            .then_rejected(DomainError::NoParent);
+        Scenario::<TodoAggregate>::given(attached.clone())
+            .when(TodoCommand::AttachToParent { parent })
+            .then_rejected(DomainError::ParentUnchanged);
+        let todo = Scenario::<TodoAggregate>::given(attached)
+            .when(TodoCommand::DetachFromParent)
+            .then_events(vec![TodoEvent::DetachFromParent { version: 3 }]);
+        assert_eq!(todo.parent, None);
+    }
+
+    #[test]
+    fn status_changes_follow_the_transition_table() {
+        let cancelled = vec![created(Guid::new()), status(Status::Cancelled, 2)];
+
+        Scenario::<TodoAggregate>::given(vec![created(Guid::new())])
+            .when(TodoCommand::StartTodo)
+            .then_events(vec![status(Status::InProgress, 2)]);
+        Scenario::<TodoAggregate>::given(vec![created(Guid::new()), status(Status::Blocked, 2)])
+            .when(TodoCommand::CompleteTodo { cascade: false })
+            .then_error(AggregateErr::InvalidTransition { from: Status::Blocked, to: Status::Complete });
+        Scenario::<TodoAggregate>::given(cancelled.clone())
+            .when(TodoCommand::StartTodo)
+            .then_error(AggregateErr::InvalidTransition { from: Status::Cancelled, to: Status::InProgress });
+        Scenario::<TodoAggregate>::given(cancelled.clone())
+            .when_event(status(Status::Complete, 3))
+            .then_error(AggregateErr::InvalidTransition { from: Status::Cancelled, to: Status::Complete });
+        Scenario::<TodoAggregate>::given(cancelled)
+            .when(TodoCommand::ReopenTodo)
+            .then_events(vec![status(Status::Incomplete, 3)]);
+    }
+
+    #[test]
+    fn histories_from_before_the_transition_table_still_load() {
+        let todo = TodoAggregate::from_events(vec![
+            created(Guid::new()),
+            status(Status::Incomplete, 2),
+            status(Status::Complete, 3),
+            status(Status::Complete, 4),
+        ]);
+
+        assert_eq!((todo.version(), todo.status), (4, Status::Complete));
+    }
+
+    #[test]
+    fn delete_command_deletes_once() {
+        let given = vec![created(Guid::new())];
+        Scenario::<TodoAggregate>::given(given.clone())
+            .when(TodoCommand::DeleteTodo)
+            .then_events(vec![TodoEvent::Delete { version: 2 }]);
+
+        let mut deleted = given;
+        deleted.push(TodoEvent::Delete { version: 2 });
+        Scenario::<TodoAggregate>::given(deleted)
+            .when(TodoCommand::DeleteTodo)
+            .then_rejected(DomainError::Deleted);
+    }
+
+    #[test]
+    fn restore_brings_back_deleted_todos_only() {
+        let given = vec![created(Guid::new()), renamed("oat milk", 2)];
+        let mut deleted = given.clone();
+        deleted.push(TodoEvent::Delete { version: 3 });
+
+        let todo = Scenario::<TodoAggregate>::given(deleted.clone())
+            .when(TodoCommand::RestoreTodo)
+            .then_events(vec![TodoEvent::Restore { version: 4 }]);
+        assert_eq!((todo.is_deleted, todo.name.as_str()), (false, "oat milk"));
+        Scenario::<TodoAggregate>::given(given.clone())
+            .when(TodoCommand::RestoreTodo)
+            .then_rejected(DomainError::NotDeleted);
+        Scenario::<TodoAggregate>::given(given)
+            .when_event(TodoEvent::Restore { version: 3 })
+            .then_error(AggregateErr::NotDeleted);
+    }
+
+    #[test]
+    fn cascade_defaults_to_off() {
+        let command: TodoCommand = serde_json::from_str(r#"{"type": "CompleteTodo"}"#).unwrap();
+        assert_eq!(command, TodoCommand::CompleteTodo { cascade: false });
+    }
+
+    #[test]
+    fn clients_cannot_send_create_commands() {
+        let command = format!(r#"{{"type": "CreateTodo", "id": "{}", "name": "forged"}}"#, Guid::new());
+        assert!(serde_json::from_str::<TodoCommand>(&command).is_err());
+        assert!(serde_json::from_str::<TodoCommand>(r#"{"type": "ChangeName", "new_name": "forged", "version": 2}"#).is_err());
+    }
+}
@@ -0,0 +1,183 @@
+//! Property tests for folding `TodoEvent`s into a `TodoAggregate`. proptest shrinks a failing
+//! case to the shortest event list that still fails and prints it, so it can be pasted into
+//! a regression test in `aggregate.rs`.
+
+use chrono::{DateTime, TimeZone, Utc};
+use proptest::prelude::*;
+use crate::guid::Guid;
+use crate::routes::todo::Status;
+use crate::services::aggregate::{can_change_status, Aggregate, AggregateErr, TodoAggregate, TodoEvent};
+use crate::services::codec::CodecKind;
+use crate::services::event_store::{EventEnvelope, EventMetadata};
+
+fn status() -> impl Strategy<Value = Status> {
+    prop_oneof![
+        Just(Status::Complete),
+        Just(Status::Incomplete),
+        Just(Status::InProgress),
+        Just(Status::Blocked),
+        Just(Status::Cancelled),
+    ]
+}
+
+/// An update without its version, which is only known once it is placed in a history.
+#[derive(Debug, Clone)]
+enum Update {
+    Rename(String),
+    SetStatus(Status),
+    SetDueDate(DateTime<Utc>),
+    ClearDueDate,
+    Move(Option<Guid>),
+    Attach,
+    Detach,
+}
+
+fn due_date() -> impl Strategy<Value = DateTime<Utc>> {
+    (0..4_000_000_000i64).prop_map(|seconds| Utc.timestamp_opt(seconds, 0).unwrap())
+}
+
+fn update() -> impl Strategy<Value = Update> {
+    prop_oneof![
+        "[a-z ]{0,8}".prop_map(Update::Rename),
+        status().prop_map(Update::SetStatus),
+        due_date().prop_map(Update::SetDueDate),
+        Just(Update::ClearDueDate),
+        prop::option::of(Just(Guid::empty())).prop_map(Update::Move),
+        Just(Update::Attach),
+        Just(Update::Detach),
+    ]
+}
+
+/// A history `TodoAggregate` accepts: `Create`, consecutive updates and possibly a final
+/// `Delete`. Status changes the transition table does not allow are left out.
+fn valid_history() -> impl Strategy<Value = Vec<TodoEvent>> {
+    (prop::collection::vec(update(), 0..40), any::<bool>()).prop_map(|(updates, deleted)| {
+        let mut events = vec![TodoEvent::Create { name: "todo".to_string(), id: Guid::empty() }];
+        let mut current = Status::Incomplete;
+        for update in updates {
+            let version = events.len() as u32 + 1;
+            events.push(match update {
+                Update::Rename(new_name) => { TodoEvent::ChangeName { new_name, version } }
+                Update::SetStatus(status) => {
+                    if !can_change_status(&current, &status) {
+                        continue;
+                    }
+                    current = status.clone();
+                    TodoEvent::ChangeStatus { status, version }
+                }
+                Update::SetDueDate(due) => { TodoEvent::SetDueDate { due, version } }
+                Update::ClearDueDate => { TodoEvent::ClearDueDate { version } }
+                Update::Move(list) => { TodoEvent::ChangeList { list, version } }
+                Update::Attach => { TodoEvent::AttachToParent { parent: Guid::empty(), version } }
+                Update::Detach => { TodoEvent::DetachFromParent { version } }
+            });
+        }
+        if deleted {
+            events.push(TodoEvent::Delete { version: events.len() as u32 + 1 });
+        }
+
+        events
+    })
+}
+
+/// Any event with a small version, so that sequences hit the next version now and then.
+fn any_event() -> impl Strategy<Value = TodoEvent> {
+    prop_oneof![
+        Just(TodoEvent::Create { name: "todo".to_string(), id: Guid::empty() }),
+        ("[a-z]{0,4}", 1..8u32).prop_map(|(new_name, version)| TodoEvent::ChangeName { new_name, version }),
+        (status(), 1..8u32).prop_map(|(status, version)| TodoEvent::ChangeStatus { status, version }),
+        (due_date(), 1..8u32).prop_map(|(due, version)| TodoEvent::SetDueDate { due, version }),
+        (1..8u32).prop_map(|version| TodoEvent::ClearDueDate { version }),
+        (1..8u32).prop_map(|version| TodoEvent::ChangeList { list: Some(Guid::empty()), version }),
+        (1..8u32).prop_map(|version| TodoEvent::AttachToParent { parent: Guid::empty(), version }),
+        (1..8u32).prop_map(|version| TodoEvent::DetachFromParent { version }),
+        (1..8u32).prop_map(|version| TodoEvent::Delete { version }),
+        (1..8u32).prop_map(|version| TodoEvent::Restore { version }),
+    ]
+}
+
+/// Offers every event to `try_apply` in turn and keeps the ones it accepts, like a store
+/// that only appends what the aggregate validated.
+fn fold_accepted(events: &[TodoEvent]) -> (TodoAggregate, Vec<TodoEvent>) {
+    let mut state = TodoAggregate::new();
+    let mut accepted = vec![];
+    for event in events {
+        if let Ok(valid_event) = state.try_apply(event.clone()) {
+            state = state.apply(&valid_event);
+            accepted.push(event.clone());
+        }
+    }
+
+    (state, accepted)
+}
+
+proptest! {
+    #[test]
+    fn valid_histories_are_accepted_at_consecutive_versions(events in valid_history()) {
+        let mut state = TodoAggregate::new();
+        for event in &events {
+            prop_assert_eq!(event.version(), state.version() + 1);
+            let valid_event = state.try_apply(event.clone());
+            prop_assert!(valid_event.is_ok(), "{:?} was rejected", event);
+            state = state.apply(&valid_event.ok().unwrap());
+        }
+
+        prop_assert_eq!(state.version(), events.len() as u32);
+        prop_assert_eq!(state, TodoAggregate::from_events(events));
+    }
+
+    #[test]
+    fn accepted_versions_strictly_increase(events in prop::collection::vec(any_event(), 0..30)) {
+        let (state, accepted) = fold_accepted(&events);
+        let versions: Vec<u32> = accepted.iter().map(TodoEvent::version).collect();
+
+        prop_assert!(versions.iter().enumerate().all(|(i, version)| *version == i as u32 + 1), "{:?}", versions);
+        prop_assert_eq!(state.version(), accepted.len() as u32);
+    }
+
+    #[test]
+    fn only_restore_is_accepted_after_delete(events in valid_history(), later in prop::collection::vec(any_event(), 1..10)) {
+        let deleted = TodoAggregate::from_events(events);
+        prop_assume!(deleted.is_deleted);
+
+        for event in later {
+            let restore = matches!(event, TodoEvent::Restore { .. });
+            let accepted = deleted.try_apply(event.clone());
+            if restore && event.version() == deleted.version() + 1 {
+                prop_assert!(accepted.is_ok());
+            } else if !restore {
+                prop_assert_eq!(accepted.err(), Some(AggregateErr::Deleted));
+            }
+        }
+    }
+
+    #[test]
+    fn replaying_stored_events_reproduces_the_state(events in prop::collection::vec(any_event(), 0..30)) {
+        let (state, accepted) = fold_accepted(&events);
+        let metadata = EventMetadata::default();
+
+        for kind in [CodecKind::Json, CodecKind::Bson, CodecKind::MessagePack, CodecKind::Cbor] {
+            let codec = kind.codec();
+            let stored: Vec<TodoEvent> = accepted
+                .iter()
+                .map(|event| {
+                    let bytes = codec.encode(&EventEnvelope::new(event.clone(), &metadata).to_raw().unwrap()).unwrap();
+                    codec.decode(&bytes).unwrap().parse::<TodoEvent>().unwrap().event
+                })
+                .collect();
+
+            prop_assert_eq!(&TodoAggregate::from_events(stored), &state, "{:?}", kind);
+        }
+    }
+
+    #[test]
+    fn snapshot_plus_tail_equals_full_replay(events in valid_history(), split in any::<prop::sample::Index>()) {
+        let split = split.index(events.len() + 1);
+        let full_replay = TodoAggregate::from_events(events.clone());
+
+        let snapshot = serde_json::to_value(TodoAggregate::from_events(events[..split].to_vec())).unwrap();
+        let resumed = TodoAggregate::from_snapshot(serde_json::from_value(snapshot).unwrap(), events[split..].to_vec());
+
+        prop_assert_eq!(resumed, full_replay);
+    }
+}
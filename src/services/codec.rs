@@ -0,0 +1,161 @@
+use std::sync::Arc;
+use mongodb::bson;
+use serde_derive::Deserialize;
+use serde_json::Value;
+use crate::services::event_store::EventEnvelope;
+
+/// Turns events into the bytes an event store keeps and back. Every stored event records the
+/// `content_type` of the codec that wrote it and is decoded with that codec, so a store can
+/// switch codecs without rewriting the events it already holds.
+pub trait EventCodec: Send + Sync {
+    fn content_type(&self) -> &'static str;
+    fn encode(&self, event: &EventEnvelope<Value>) -> Result<Vec<u8>, String>;
+    fn decode(&self, bytes: &[u8]) -> Result<EventEnvelope<Value>, String>;
+}
+
+pub struct JsonCodec;
+
+impl EventCodec for JsonCodec {
+    fn content_type(&self) -> &'static str {
+        "application/json"
+    }
+
+    fn encode(&self, event: &EventEnvelope<Value>) -> Result<Vec<u8>, String> {
+        serde_json::to_vec(event).map_err(|err| format!("Could not encode event as JSON: {}", err))
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Result<EventEnvelope<Value>, String> {
+        serde_json::from_slice(bytes).map_err(|err| format!("Could not decode JSON event: {}", err))
+    }
+}
+
+pub struct BsonCodec;
+
+impl EventCodec for BsonCodec {
+    fn content_type(&self) -> &'static str {
+        "application/bson"
+    }
+
+    fn encode(&self, event: &EventEnvelope<Value>) -> Result<Vec<u8>, String> {
+        bson::to_vec(event).map_err(|err| format!("Could not encode event as BSON: {}", err))
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Result<EventEnvelope<Value>, String> {
+        bson::from_slice(bytes).map_err(|err| format!("Could not decode BSON event: {}", err))
+    }
+}
+
+pub struct MessagePackCodec;
+
+impl EventCodec for MessagePackCodec {
+    fn content_type(&self) -> &'static str {
+        "application/msgpack"
+    }
+
+    fn encode(&self, event: &EventEnvelope<Value>) -> Result<Vec<u8>, String> {
+        // Field names are kept so upcasters can find fields in old payloads.
+        rmp_serde::to_vec_named(event).map_err(|err| format!("Could not encode event as MessagePack: {}", err))
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Result<EventEnvelope<Value>, String> {
+        rmp_serde::from_slice(bytes).map_err(|err| format!("Could not decode MessagePack event: {}", err))
+    }
+}
+
+pub struct CborCodec;
+
+impl EventCodec for CborCodec {
+    fn content_type(&self) -> &'static str {
+        "application/cbor"
+    }
+
+    fn encode(&self, event: &EventEnvelope<Value>) -> Result<Vec<u8>, String> {
+        let mut bytes = vec![];
+        ciborium::ser::into_writer(event, &mut bytes)
+            .map_err(|err| format!("Could not encode event as CBOR: {}", err))?;
+
+        Ok(bytes)
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Result<EventEnvelope<Value>, String> {
+        ciborium::de::from_reader(bytes).map_err(|err| format!("Could not decode CBOR event: {}", err))
+    }
+}
+
+/// The codec an event store writes new events with, as named in configuration.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum CodecKind {
+    Json,
+    Bson,
+    #[serde(rename = "msgpack")]
+    MessagePack,
+    Cbor,
+}
+
+impl CodecKind {
+    const ALL: [CodecKind; 4] = [CodecKind::Json, CodecKind::Bson, CodecKind::MessagePack, CodecKind::Cbor];
+
+    pub fn codec(&self) -> Arc<dyn EventCodec> {
+        match self {
+            CodecKind::Json => { Arc::new(JsonCodec) }
+            CodecKind::Bson => { Arc::new(BsonCodec) }
+            CodecKind::MessagePack => { Arc::new(MessagePackCodec) }
+            CodecKind::Cbor => { Arc::new(CborCodec) }
+        }
+    }
+}
+
+/// Decodes an event with whichever codec wrote it.
+pub fn decode(content_type: &str, bytes: &[u8]) -> Result<EventEnvelope<Value>, String> {
+    CodecKind::ALL
+        .iter()
+        .map(|kind| kind.codec())
+        .find(|codec| codec.content_type() == content_type)
+        .ok_or_else(|| format!("No codec for stored content type {}", content_type))?
+        .decode(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::guid::Guid;
+    use crate::routes::todo::Status;
+    use crate::services::aggregate::TodoEvent;
+    use crate::services::codec::{decode, CodecKind};
+    use crate::services::event_store::{EventEnvelope, EventMetadata};
+
+    #[test]
+    fn every_codec_round_trips_every_event() {
+        let id = Guid::new();
+        let metadata = EventMetadata {
+            actor: Some("ada".to_string()),
+            correlation_id: Some(Guid::new().to_string()),
+            causation_id: None,
+        };
+        let events = [
+            TodoEvent::Create { name: "Buy milk".to_string(), id },
+            TodoEvent::ChangeName { new_name: "Buy oat milk".to_string(), version: 2 },
+            TodoEvent::ChangeStatus { status: Status::Complete, version: 3 },
+            TodoEvent::Delete { version: 4 },
+        ];
+
+        for kind in CodecKind::ALL {
+            let codec = kind.codec();
+            for event in &events {
+                let envelope = EventEnvelope::new(event.clone(), &metadata);
+                let encoded = codec.encode(&envelope.to_raw().unwrap()).unwrap();
+                let decoded = decode(codec.content_type(), &encoded).unwrap().parse::<TodoEvent>().unwrap();
+
+                assert_eq!(decoded.event, envelope.event, "{:?}", kind);
+                assert_eq!(decoded.event_id, envelope.event_id, "{:?}", kind);
+                assert_eq!(decoded.recorded_at, envelope.recorded_at, "{:?}", kind);
+                assert_eq!(decoded.actor, envelope.actor, "{:?}", kind);
+            }
+        }
+    }
+
+    #[test]
+    fn unknown_content_types_are_rejected() {
+        assert!(decode("application/xml", b"<event/>").is_err());
+    }
+}
@@ -1,22 +1,43 @@
+use std::collections::HashMap;
 use std::error::Error;
 use std::fmt::{Debug, Display, Formatter};
-use mongodb::{Client, Collection};
-use mongodb::bson::doc;
+use std::sync::RwLock;
+use mongodb::{Client, Collection, IndexModel};
+use mongodb::bson::{doc, Document};
+use mongodb::options::{IndexOptions, ReplaceOptions};
 use rocket::futures::StreamExt;
+use crate::config::MongoConfig;
 use crate::guid::Guid;
 use crate::routes::todo::Todo;
+use crate::services::event_store::is_duplicate_key;
 
 pub type DataAccessResult<T> = Result<T, DataAccessErr>;
 
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DataAccessErrKind {
+    NotFound,
+    /// The backing store could not complete the operation.
+    Unavailable,
+}
+
 #[derive(Debug)]
 pub struct DataAccessErr {
     pub message: String,
+    pub kind: DataAccessErrKind,
 }
 
 impl DataAccessErr {
-    fn new(message: &str) -> DataAccessErr {
+    pub fn new(message: &str) -> DataAccessErr {
+        DataAccessErr {
+            message: message.to_string(),
+            kind: DataAccessErrKind::Unavailable,
+        }
+    }
+
+    pub fn not_found(message: &str) -> DataAccessErr {
         DataAccessErr {
-            message: message.to_string()
+            message: message.to_string(),
+            kind: DataAccessErrKind::NotFound,
         }
     }
 }
@@ -31,67 +52,192 @@ impl Error for DataAccessErr {}
 
 #[async_trait]
 pub trait TodoRepository: Send + Sync {
-    async fn insert(&self, todo: Todo) -> DataAccessResult<Todo>;
-    async fn update(&self, todo: Todo) -> DataAccessResult<Todo>;
     async fn get_by_id(&self, id: Guid) -> DataAccessResult<Todo>;
+    /// Every todo that is not deleted.
     async fn list(&self) -> DataAccessResult<Vec<Todo>>;
+    /// Every deleted todo that has not been purged yet.
+    async fn list_deleted(&self) -> DataAccessResult<Vec<Todo>>;
+    /// Inserts the todo or replaces the stored one with the same id.
+    async fn upsert(&self, todo: Todo) -> DataAccessResult<Todo>;
+    /// Like `upsert`, but leaves a stored todo that is already at the same or a later version
+    /// alone. Returns whether the todo was stored.
+    async fn upsert_if_newer(&self, todo: Todo) -> DataAccessResult<bool>;
+    async fn delete(&self, id: Guid) -> DataAccessResult<()>;
 }
 
 pub struct MongoTodoRepository {
     collection: Collection<Todo>,
 }
 
+/// Name of the unique index on the todo id.
+const TODO_ID_INDEX: &str = "id_1";
+
 impl MongoTodoRepository {
-    pub fn new(client: &Client) -> MongoTodoRepository {
-        MongoTodoRepository {
-            collection: client.database("rust-test").collection("todo")
-        }
+    /// Connects to the todo collection and makes sure no two documents share a todo id.
+    pub async fn init(client: &Client, config: &MongoConfig) -> Result<MongoTodoRepository, String> {
+        let repo = MongoTodoRepository {
+            collection: client.database(&config.database).collection(&config.todo_collection)
+        };
+        let index = IndexModel::builder()
+            .keys(doc! { "id": 1 })
+            .options(IndexOptions::builder().unique(true).name(TODO_ID_INDEX.to_string()).build())
+            .build();
+        repo.collection
+            .create_index(index, None).await
+            .map_err(|err| format!("Could not create the todo index on {}: {}", config.todo_collection, err))?;
+
+        Ok(repo)
+    }
+
+    async fn find(&self, query: Document) -> DataAccessResult<Vec<Todo>> {
+        let mut cursor = self.collection
+            .find(query, None).await
+            .map_err(|_| DataAccessErr::new("Could not list todos"))?;
+        let mut results: Vec<Todo> = vec![];
+
+        while let Some(todo) = cursor.next().await {
+            results.push(todo.map_err(|_| DataAccessErr::new("Could not deserialize todo"))?);
+        };
+
+        Ok(results)
     }
 }
 
 #[async_trait]
 impl TodoRepository for MongoTodoRepository {
-    async fn insert(&self, todo: Todo) -> DataAccessResult<Todo> {
-        self.collection
-            .insert_one(&todo, None).await
-            .map_err(|_| DataAccessErr::new("Could not Insert todo {todo:?}"))?;
+    async fn get_by_id(&self, id: Guid) -> DataAccessResult<Todo> {
+        let query = doc! {
+          "id": id.to_string()
+        };
+        match self.collection
+            .find_one(query, None).await
+            .map_err(|_| DataAccessErr::new("Could not find todo"))? {
+            None => { Err(DataAccessErr::not_found("Could not find todo")) }
+            Some(todo) => { Ok(todo) }
+        }
+    }
 
-        Ok(todo)
+    async fn list(&self) -> DataAccessResult<Vec<Todo>> {
+        self.find(doc! { "deleted_at": null }).await
     }
 
-    async fn update(&self, todo: Todo) -> DataAccessResult<Todo> {
+    async fn list_deleted(&self) -> DataAccessResult<Vec<Todo>> {
+        self.find(doc! { "deleted_at": { "$ne": null } }).await
+    }
+
+    async fn upsert(&self, todo: Todo) -> DataAccessResult<Todo> {
         let query = doc! {
-            "_id": todo.id.to_string()
+            "id": todo.id.to_string()
         };
+        let options = ReplaceOptions::builder()
+            .upsert(true)
+            .build();
         self.collection
-            .find_one_and_replace(query, &todo, None).await
-            .map_err(|_| DataAccessErr::new("Could not update todo {todo:?}"))?;
+            .replace_one(query, &todo, options).await
+            .map_err(|_| DataAccessErr::new("Could not upsert todo"))?;
 
         Ok(todo)
     }
 
-    async fn get_by_id(&self, id: Guid) -> DataAccessResult<Todo> {
+    async fn upsert_if_newer(&self, todo: Todo) -> DataAccessResult<bool> {
         let query = doc! {
-          "_id": id.to_string()  
+            "id": todo.id.to_string(),
+            "version": { "$lt": todo.version }
         };
-        match self.collection
-            .find_one(query, None).await
-            .map_err(|_| DataAccessErr::new("Could not find todo"))? {
-            None => { Err(DataAccessErr::new("Could not find todo")) }
-            Some(todo) => { Ok(todo) }
+        let options = ReplaceOptions::builder()
+            .upsert(true)
+            .build();
+        match self.collection.replace_one(query, &todo, options).await {
+            Ok(_) => { Ok(true) }
+            // A newer stored todo does not match, and the unique index rejects the insert the
+            // upsert falls back to.
+            Err(err) if is_duplicate_key(&err, TODO_ID_INDEX) => { Ok(false) }
+            Err(_) => { Err(DataAccessErr::new("Could not upsert todo")) }
+        }
+    }
+
+    async fn delete(&self, id: Guid) -> DataAccessResult<()> {
+        let query = doc! {
+            "id": id.to_string()
+        };
+        self.collection
+            .delete_one(query, None).await
+            .map_err(|_| DataAccessErr::new("Could not delete todo"))?;
+
+        Ok(())
+    }
+}
+
+/// Process-local read model store, used alongside the in-memory event store when no
+/// MongoDB instance is available.
+pub struct InMemoryTodoRepository {
+    todos: RwLock<HashMap<Guid, Todo>>,
+}
+
+impl InMemoryTodoRepository {
+    pub fn new() -> InMemoryTodoRepository {
+        InMemoryTodoRepository {
+            todos: RwLock::new(HashMap::new())
+        }
+    }
+}
+
+#[async_trait]
+impl TodoRepository for InMemoryTodoRepository {
+    async fn get_by_id(&self, id: Guid) -> DataAccessResult<Todo> {
+        let todos = self.todos
+            .read()
+            .map_err(|_| DataAccessErr::new("Could not find todo"))?;
+
+        match todos.get(&id) {
+            None => { Err(DataAccessErr::not_found("Could not find todo")) }
+            Some(todo) => { Ok(todo.clone()) }
         }
     }
 
     async fn list(&self) -> DataAccessResult<Vec<Todo>> {
-        let mut cursor = self.collection
-            .find(None, None).await
+        let todos = self.todos
+            .read()
             .map_err(|_| DataAccessErr::new("Could not list todos"))?;
-        let mut results: Vec<Todo> = vec![];
 
-        while let Some(todo) = cursor.next().await {
-            results.push(todo.map_err(|_| DataAccessErr::new("Could not deserialize todo"))?);
-        };
+        Ok(todos.values().filter(|todo| todo.deleted_at.is_none()).cloned().collect())
+    }
 
-        Ok(results)
+    async fn list_deleted(&self) -> DataAccessResult<Vec<Todo>> {
+        let todos = self.todos
+            .read()
+            .map_err(|_| DataAccessErr::new("Could not list todos"))?;
+
+        Ok(todos.values().filter(|todo| todo.deleted_at.is_some()).cloned().collect())
     }
-}
\ No newline at end of file
+
+    async fn upsert(&self, todo: Todo) -> DataAccessResult<Todo> {
+        let mut todos = self.todos
+            .write()
+            .map_err(|_| DataAccessErr::new("Could not upsert todo"))?;
+        todos.insert(todo.id, todo.clone());
+
+        Ok(todo)
+    }
+
+    async fn upsert_if_newer(&self, todo: Todo) -> DataAccessResult<bool> {
+        let mut todos = self.todos
+            .write()
+            .map_err(|_| DataAccessErr::new("Could not upsert todo"))?;
+        if todos.get(&todo.id).is_some_and(|stored| stored.version >= todo.version) {
+            return Ok(false);
+        }
+        todos.insert(todo.id, todo);
+
+        Ok(true)
+    }
+
+    async fn delete(&self, id: Guid) -> DataAccessResult<()> {
+        let mut todos = self.todos
+            .write()
+            .map_err(|_| DataAccessErr::new("Could not delete todo"))?;
+        todos.remove(&id);
+
+        Ok(())
+    }
+}
@@ -1,105 +1,1172 @@
-use std::cmp::Ordering;
-use mongodb::{Client, Collection};
-use mongodb::bson::doc;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::ops::Range;
+use std::sync::{Arc, RwLock};
+use chrono::{DateTime, Utc};
+use mongodb::{Client, Collection, IndexModel};
+use mongodb::bson::{doc, from_document, Binary, Document};
+use mongodb::bson::spec::BinarySubtype;
+use mongodb::error::{ErrorKind, WriteFailure};
+use mongodb::options::{FindOneAndUpdateOptions, FindOneOptions, FindOptions, IndexOptions, ReplaceOptions, ReturnDocument, UpdateOptions};
 use rocket::futures::StreamExt;
+use rocket::tokio::sync::{Mutex, MutexGuard};
+use serde::de::DeserializeOwned;
 use serde_derive::{Deserialize, Serialize};
+use serde_json::Value;
+use crate::config::MongoConfig;
 use crate::guid::Guid;
-use crate::services::aggregate::{Aggregate, AggregateErr, TodoAggregate, TodoEvent, ValidTodoEvent};
+use crate::services::aggregate::{Aggregate, DomainEvent, TodoAggregate, TodoEvent};
+use crate::services::codec::{self, EventCodec, JsonCodec};
+use crate::services::upcast::{upcast, CURRENT_SCHEMA_VERSION};
 
+/// Who and what caused events to be appended, shared by every event a single request produces.
+#[derive(Debug, Clone, Default)]
+pub struct EventMetadata {
+    pub actor: Option<String>,
+    /// Ties together every event produced on behalf of one originating request.
+    pub correlation_id: Option<String>,
+    /// Id of the message or event that directly caused these events.
+    pub causation_id: Option<String>,
+}
+
+/// A stored event wrapped with its metadata. Folding only ever looks at `event`.
+/// Stored events are upcast to `CURRENT_SCHEMA_VERSION` while they are deserialized, so
+/// every backend reads old events in the current shape.
+///
+/// Event stores keep `EventEnvelope<Value>`s and never look inside the event; typed
+/// envelopes are converted with `to_raw` and `parse`.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(try_from = "Value", bound(deserialize = "E: DeserializeOwned"))]
+pub struct EventEnvelope<E> {
+    pub schema_version: u32,
+    pub event_id: Guid,
+    pub recorded_at: DateTime<Utc>,
+    pub actor: Option<String>,
+    pub correlation_id: Option<String>,
+    pub causation_id: Option<String>,
+    #[serde(flatten)]
+    pub event: E,
+}
+
+/// `EventEnvelope` in the current schema, which is what upcast payloads are read into.
+#[derive(Deserialize)]
+struct CurrentEnvelope<E> {
+    schema_version: u32,
+    event_id: Guid,
+    recorded_at: DateTime<Utc>,
+    actor: Option<String>,
+    correlation_id: Option<String>,
+    causation_id: Option<String>,
+    #[serde(flatten)]
+    event: E,
+}
+
+impl<E: DeserializeOwned> TryFrom<Value> for EventEnvelope<E> {
+    type Error = String;
+
+    fn try_from(value: Value) -> Result<EventEnvelope<E>, String> {
+        let current: CurrentEnvelope<E> = serde_json::from_value(upcast(value)?)
+            .map_err(|err| format!("Could not read stored event: {}", err))?;
+
+        Ok(EventEnvelope {
+            schema_version: current.schema_version,
+            event_id: current.event_id,
+            recorded_at: current.recorded_at,
+            actor: current.actor,
+            correlation_id: current.correlation_id,
+            causation_id: current.causation_id,
+            event: current.event,
+        })
+    }
+}
+
+impl<E> EventEnvelope<E> {
+    pub fn new(event: E, metadata: &EventMetadata) -> EventEnvelope<E> {
+        EventEnvelope {
+            schema_version: CURRENT_SCHEMA_VERSION,
+            event_id: Guid::new(),
+            recorded_at: Utc::now(),
+            actor: metadata.actor.clone(),
+            correlation_id: metadata.correlation_id.clone(),
+            causation_id: metadata.causation_id.clone(),
+            event,
+        }
+    }
+
+    fn with_event<F>(self, event: F) -> EventEnvelope<F> {
+        EventEnvelope {
+            schema_version: self.schema_version,
+            event_id: self.event_id,
+            recorded_at: self.recorded_at,
+            actor: self.actor,
+            correlation_id: self.correlation_id,
+            causation_id: self.causation_id,
+            event,
+        }
+    }
+}
+
+impl<E: serde::Serialize + Clone> EventEnvelope<E> {
+    /// The envelope with its event as plain data, the way event stores keep it.
+    pub fn to_raw(&self) -> EventStoreResult<EventEnvelope<Value>> {
+        let event = serde_json::to_value(&self.event)
+            .map_err(|err| EventStoreErr::StorageErr(format!("Could not serialize event: {}", err)))?;
+
+        Ok(self.clone().with_event(event))
+    }
+}
+
+impl<E: DomainEvent> EventEnvelope<E> {
+    pub fn version(&self) -> u32 {
+        self.event.version()
+    }
+}
+
+impl EventEnvelope<Value> {
+    /// Reads the stored event as `E`.
+    pub fn parse<E: DeserializeOwned>(self) -> EventStoreResult<EventEnvelope<E>> {
+        let event = serde_json::from_value(self.event.clone())
+            .map_err(|err| EventStoreErr::StorageErr(format!("Could not read stored event: {}", err)))?;
+
+        Ok(self.with_event(event))
+    }
+}
+
+/// An event together with its place in the global log. Positions grow with every append
+/// across all streams, so a reader that remembers the last position it handled can resume
+/// from there.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(bound(deserialize = "E: DeserializeOwned"))]
+pub struct RecordedEvent<E> {
+    pub position: u64,
+    pub stream: String,
+    /// Version of the stream right after this event.
+    pub version: u32,
+    pub event: EventEnvelope<E>,
+}
+
+impl RecordedEvent<Value> {
+    pub fn parse<E: DeserializeOwned>(self) -> EventStoreResult<RecordedEvent<E>> {
+        Ok(RecordedEvent {
+            position: self.position,
+            stream: self.stream,
+            version: self.version,
+            event: self.event.parse()?,
+        })
+    }
+}
+
+/// The events of one stream in version order.
+#[derive(Debug, Clone)]
+pub struct StoredStream {
+    pub name: String,
+    pub events: Vec<EventEnvelope<Value>>,
+}
+
+/// Whether `stream` belongs to `category`, the aggregate type its name starts with.
+fn in_category(stream: &str, category: &str) -> bool {
+    stream
+        .strip_prefix(category)
+        .is_some_and(|rest| rest.starts_with('-'))
+}
+
+/// Folded aggregate state captured at `version`, so loading only has to replay the events
+/// appended after it.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Snapshot {
+    #[serde(rename = "_id")]
+    pub stream: String,
+    pub version: u32,
+    pub state: Value,
+}
+
+pub type EventStoreResult<T> = Result<T, EventStoreErr>;
+
+#[derive(Debug)]
+pub enum EventStoreErr {
+    /// The stream no longer is at the version the caller read before deciding on its events.
+    ConcurrencyErr { stream: String, expected_version: u32 },
+    NotFound(String),
+    StorageErr(String),
+}
+
+impl Display for EventStoreErr {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EventStoreErr::ConcurrencyErr { stream, expected_version } => {
+                write!(f, "Events for {} were changed after version {} was read", stream, expected_version)
+            }
+            EventStoreErr::NotFound(stream) => {
+                write!(f, "Could not find events for {}", stream)
+            }
+            EventStoreErr::StorageErr(message) => {
+                f.write_str(message.as_str())
+            }
+        }
+    }
+}
+
+impl Error for EventStoreErr {}
+
+/// Stores streams of events for any aggregate type. Streams are addressed by name, and the
+/// events are kept as plain data; `EventSourcedRepository` adds the typed view.
+#[async_trait]
+pub trait EventStore: Send + Sync {
+    async fn get(&self, stream: &str) -> EventStoreResult<Vec<EventEnvelope<Value>>>;
+    /// Every stream of `category`, the aggregate type that prefixes the stream names.
+    async fn list(&self, category: &str) -> EventStoreResult<Vec<StoredStream>>;
+    /// Appends `events` to the stream only if it is still at `expected_version`, otherwise
+    /// nothing is written and `EventStoreErr::ConcurrencyErr` is returned. An `expected_version`
+    /// of 0 starts a new stream. The events get the versions following `expected_version`.
+    /// Returns the appended events with the positions they got.
+    async fn append(&self, stream: &str, expected_version: u32, events: Vec<EventEnvelope<Value>>) -> EventStoreResult<Vec<RecordedEvent<Value>>>;
+    /// Reads up to `limit` events of every stream with a position greater than
+    /// `after_position`, in position order.
+    async fn read_all(&self, after_position: u64, limit: usize) -> EventStoreResult<Vec<RecordedEvent<Value>>>;
+    /// Like `read_all`, but only returns events of the streams of `category`.
+    async fn read_category(&self, category: &str, after_position: u64, limit: usize) -> EventStoreResult<Vec<RecordedEvent<Value>>>;
+    /// Position of the newest event, 0 while the log is empty.
+    async fn last_position(&self) -> EventStoreResult<u64>;
+    /// Returns the events of the stream with a version greater than `version`.
+    async fn get_events_after(&self, stream: &str, version: u32) -> EventStoreResult<Vec<EventEnvelope<Value>>>;
+    async fn get_snapshot(&self, stream: &str) -> EventStoreResult<Option<Snapshot>>;
+    /// Stores `snapshot` as the latest snapshot of its stream, replacing any older one.
+    async fn save_snapshot(&self, snapshot: &Snapshot) -> EventStoreResult<()>;
+    /// Last position the named subscriber has processed, if it ever saved one.
+    async fn get_checkpoint(&self, name: &str) -> EventStoreResult<Option<u64>>;
+    async fn save_checkpoint(&self, name: &str, position: u64) -> EventStoreResult<()>;
+    /// Permanently removes the stream and its snapshot, leaving a gap in the global log. Fails
+    /// with `ConcurrencyErr` unless the stream is still at `expected_version`, so nothing
+    /// appended since the caller last read it is lost. Positions are never handed out again,
+    /// so readers past the gap miss nothing.
+    async fn delete_stream(&self, stream: &str, expected_version: u32) -> EventStoreResult<()>;
+}
+
+/// One event of a stream, stored as its own document. A unique index on
+/// (`stream_id`, `version`) makes MongoDB reject a second writer appending the same version.
+/// Positions come from a counter document, so no two appends are given the same one.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct StoredEvent {
+    position: u64,
+    stream_id: String,
+    version: u32,
+    event_id: Guid,
+    /// Names the codec `payload` was written with.
+    content_type: String,
+    payload: Binary,
+}
+
+impl StoredEvent {
+    fn encode(position: u64, stream: &str, version: u32, event: &EventEnvelope<Value>, codec: &dyn EventCodec) -> EventStoreResult<StoredEvent> {
+        let bytes = codec.encode(event).map_err(EventStoreErr::StorageErr)?;
+
+        Ok(StoredEvent {
+            position,
+            stream_id: stream.to_string(),
+            version,
+            event_id: event.event_id,
+            content_type: codec.content_type().to_string(),
+            payload: Binary { subtype: BinarySubtype::Generic, bytes },
+        })
+    }
+
+    fn decode(&self) -> EventStoreResult<EventEnvelope<Value>> {
+        codec::decode(&self.content_type, &self.payload.bytes).map_err(EventStoreErr::StorageErr)
+    }
+
+    fn recorded(self) -> EventStoreResult<RecordedEvent<Value>> {
+        Ok(RecordedEvent {
+            event: self.decode()?,
+            position: self.position,
+            stream: self.stream_id,
+            version: self.version,
+        })
+    }
+}
+
+/// Position a named subscriber has processed up to.
 #[derive(Debug, Serialize, Deserialize)]
-pub struct TodoEventColl {
+struct Checkpoint {
+    #[serde(rename = "_id")]
+    name: String,
+    position: u64,
+}
+
+/// A todo's events as they were stored before each event got its own document.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct TodoEventColl {
     #[serde(rename = "_id")]
-    pub id: Guid,
-    events: Vec<TodoEvent>,
+    id: Guid,
+    events: Vec<EventEnvelope<TodoEvent>>,
+}
+
+pub struct MongoEventStore {
+    codec: Arc<dyn EventCodec>,
+    events: Collection<StoredEvent>,
+    snapshots: Collection<Snapshot>,
+    checkpoints: Collection<Checkpoint>,
+    counters: Collection<Document>,
+    /// Streams written before events were stored individually, one document per todo.
+    legacy_streams: Collection<TodoEventColl>,
+    /// Appends from this process take turns, so their positions are committed in increasing
+    /// order for subscribers reading the log.
+    append_lock: Mutex<()>,
+    /// Identifies this instance as the holder of the writer lease.
+    instance: Guid,
+}
+
+/// Counter holding the last position handed out to an event.
+const POSITION_COUNTER: &str = "position";
+/// Name of the unique (`stream_id`, `version`) index, the one MongoDB's default would give it.
+const STREAM_VERSION_INDEX: &str = "stream_id_1_version_1";
+/// Counter document naming the one instance that may append. Appends from two instances could
+/// commit their positions out of order, and readers would skip the one committed last.
+const WRITER_LEASE: &str = "writer";
+/// How long the lease outlives the last append of its holder.
+const WRITER_LEASE_MILLIS: i64 = 30_000;
+/// Index MongoDB keeps on `_id`.
+const ID_INDEX: &str = "_id_";
+
+/// Matches documents whose `field` is a bare id, as stream names were before they were
+/// prefixed with the aggregate type.
+fn is_unprefixed(field: &str) -> Document {
+    doc! { "$expr": { "$eq": [{ "$strLenCP": format!("${}", field) }, 36] } }
 }
 
-impl TodoEventColl {
-    pub fn version(mut self) -> u32 {
-        match self.events
-            .last() {
-            None => {
-                0
+impl MongoEventStore {
+    /// Connects to the configured collections, takes the writer lease and makes sure the
+    /// (stream, version) index exists.
+    pub async fn init(mongodb: &Client, config: &MongoConfig) -> Result<MongoEventStore, String> {
+        let database = mongodb.database(&config.database);
+        let store = MongoEventStore {
+            codec: config.codec.codec(),
+            events: database.collection(&config.event_collection),
+            snapshots: database.collection(&config.snapshot_collection),
+            checkpoints: database.collection(&config.checkpoint_collection),
+            counters: database.collection(&config.counter_collection),
+            legacy_streams: database.collection(&config.legacy_event_collection),
+            append_lock: Mutex::new(()),
+            instance: Guid::new(),
+        };
+
+        store.acquire_writer_lease().await
+            .map_err(|err| format!("Could not become the writer of {}: {}", config.event_collection, err))?;
+        store.sync_position_counter().await
+            .map_err(|err| format!("Could not set up the position counter: {}", err))?;
+        store.assign_missing_positions().await
+            .map_err(|err| format!("Could not assign positions to stored events: {}", err))?;
+        store.encode_nested_events().await
+            .map_err(|err| format!("Could not encode stored events: {}", err))?;
+        store.prefix_stream_names().await
+            .map_err(|err| format!("Could not rename stored streams: {}", err))?;
+        let indexes = [
+            IndexModel::builder()
+                .keys(doc! { "stream_id": 1, "version": 1 })
+                .options(IndexOptions::builder().unique(true).name(STREAM_VERSION_INDEX.to_string()).build())
+                .build(),
+            IndexModel::builder()
+                .keys(doc! { "position": 1 })
+                .options(IndexOptions::builder().unique(true).build())
+                .build(),
+        ];
+        store.events
+            .create_indexes(indexes, None).await
+            .map_err(|err| format!("Could not create the event indexes on {}: {}", config.event_collection, err))?;
+
+        Ok(store)
+    }
+
+    /// Takes the writer lease, waiting up to one lease period for another instance's lease to
+    /// run out, as it does while that instance is being replaced.
+    async fn acquire_writer_lease(&self) -> EventStoreResult<()> {
+        let deadline = Utc::now() + chrono::Duration::milliseconds(WRITER_LEASE_MILLIS);
+        while !self.renew_writer_lease().await? {
+            if Utc::now() > deadline {
+                return Err(EventStoreErr::StorageErr("Another instance is writing to the event store".to_string()));
+            }
+            rocket::tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+        }
+
+        Ok(())
+    }
+
+    /// Extends this instance's writer lease, or takes over one that ran out. `false` while
+    /// another instance holds it.
+    async fn renew_writer_lease(&self) -> EventStoreResult<bool> {
+        let now = Utc::now().timestamp_millis();
+        let query = doc! {
+            "_id": WRITER_LEASE,
+            "$or": [{ "holder": self.instance.to_string() }, { "expires_at": { "$lt": now } }]
+        };
+        let update = doc! {
+            "$set": { "holder": self.instance.to_string(), "expires_at": now + WRITER_LEASE_MILLIS }
+        };
+        let options = UpdateOptions::builder()
+            .upsert(true)
+            .build();
+
+        match self.counters.update_one(query, update, options).await {
+            Ok(_) => { Ok(true) }
+            // The lease belongs to another instance, so the upsert's insert collides with it.
+            Err(err) if is_duplicate_key(&err, ID_INDEX) => { Ok(false) }
+            Err(_) => { Err(EventStoreErr::StorageErr("Could not renew the writer lease".to_string())) }
+        }
+    }
+
+    /// Waits for this process's turn to write and makes sure it still holds the writer lease.
+    async fn lock_appends(&self) -> EventStoreResult<MutexGuard<'_, ()>> {
+        let guard = self.append_lock.lock().await;
+        if !self.renew_writer_lease().await? {
+            return Err(EventStoreErr::StorageErr("Another instance is writing to the event store".to_string()));
+        }
+
+        Ok(guard)
+    }
+
+    /// Moves the position counter up to the newest stored event, for logs written before
+    /// positions were counted.
+    async fn sync_position_counter(&self) -> EventStoreResult<()> {
+        let last_position = self.last_position().await?;
+        let options = UpdateOptions::builder()
+            .upsert(true)
+            .build();
+
+        self.counters
+            .update_one(doc! { "_id": POSITION_COUNTER }, doc! { "$max": { "value": last_position as i64 } }, options).await
+            .map(|_| ())
+            .map_err(|_| EventStoreErr::StorageErr("Could not update the position counter".to_string()))
+    }
+
+    /// Reserves `count` positions and returns the first of them. Positions of failed appends
+    /// stay unused.
+    async fn allocate_positions(&self, count: usize) -> EventStoreResult<u64> {
+        let options = FindOneAndUpdateOptions::builder()
+            .upsert(true)
+            .return_document(ReturnDocument::After)
+            .build();
+        let counter = self.counters
+            .find_one_and_update(doc! { "_id": POSITION_COUNTER }, doc! { "$inc": { "value": count as i64 } }, options).await
+            .map_err(|_| EventStoreErr::StorageErr("Could not allocate event positions".to_string()))?
+            .ok_or_else(|| EventStoreErr::StorageErr("Position counter is missing".to_string()))?;
+        let last = counter
+            .get_i64("value")
+            .map_err(|_| EventStoreErr::StorageErr("Position counter is not a number".to_string()))?;
+
+        Ok(last as u64 + 1 - count as u64)
+    }
+
+    /// Events stored before positions existed are numbered after the newest positioned
+    /// event, stream by stream in version order.
+    async fn assign_missing_positions(&self) -> EventStoreResult<()> {
+        let unpositioned = self.events.clone_with_type::<Document>();
+        let options = FindOptions::builder()
+            .sort(doc! { "stream_id": 1, "version": 1 })
+            .build();
+        let mut cursor = unpositioned
+            .find(doc! { "position": { "$exists": false } }, options).await
+            .map_err(|_| EventStoreErr::StorageErr("Could not find events".to_string()))?;
+
+        while let Some(result) = cursor.next().await {
+            let stored = result.map_err(|_| EventStoreErr::StorageErr("Could not deserialize".to_string()))?;
+            let id = stored
+                .get("_id")
+                .ok_or_else(|| EventStoreErr::StorageErr("Stored event has no _id".to_string()))?;
+            let position = self.allocate_positions(1).await?;
+            unpositioned
+                .update_one(doc! { "_id": id }, doc! { "$set": { "position": position as i64 } }, None).await
+                .map_err(|_| EventStoreErr::StorageErr("Could not assign event position".to_string()))?;
+        }
+
+        Ok(())
+    }
+
+    /// Events stored as nested `event` documents before codecs existed are rewritten with
+    /// the configured codec.
+    async fn encode_nested_events(&self) -> EventStoreResult<()> {
+        let nested = self.events.clone_with_type::<Document>();
+        let mut cursor = nested
+            .find(doc! { "payload": { "$exists": false } }, None).await
+            .map_err(|_| EventStoreErr::StorageErr("Could not find events".to_string()))?;
+
+        while let Some(result) = cursor.next().await {
+            let stored = result.map_err(|_| EventStoreErr::StorageErr("Could not deserialize".to_string()))?;
+            let id = stored
+                .get("_id")
+                .ok_or_else(|| EventStoreErr::StorageErr("Stored event has no _id".to_string()))?;
+            let event: EventEnvelope<Value> = stored
+                .get_document("event")
+                .map_err(|_| EventStoreErr::StorageErr("Stored event has no payload".to_string()))
+                .and_then(|event| {
+                    from_document(event.clone()).map_err(|err| EventStoreErr::StorageErr(err.to_string()))
+                })?;
+            let payload = self.codec.encode(&event).map_err(EventStoreErr::StorageErr)?;
+            let update = doc! {
+                "$set": {
+                    "event_id": event.event_id.to_string(),
+                    "content_type": self.codec.content_type(),
+                    "payload": Binary { subtype: BinarySubtype::Generic, bytes: payload },
+                },
+                "$unset": { "event": "" }
+            };
+            nested
+                .update_one(doc! { "_id": id }, update, None).await
+                .map_err(|_| EventStoreErr::StorageErr("Could not encode stored event".to_string()))?;
+        }
+
+        Ok(())
+    }
+
+    /// Streams stored before stream names carried the aggregate type all belong to todos.
+    /// Their snapshots are dropped and taken again as the todos change.
+    async fn prefix_stream_names(&self) -> EventStoreResult<()> {
+        let rename = vec![doc! {
+            "$set": { "stream_id": { "$concat": [format!("{}-", TodoAggregate::TYPE), "$stream_id"] } }
+        }];
+        self.events
+            .update_many(is_unprefixed("stream_id"), rename, None).await
+            .map_err(|_| EventStoreErr::StorageErr("Could not rename streams".to_string()))?;
+        self.snapshots
+            .delete_many(is_unprefixed("_id"), None).await
+            .map_err(|_| EventStoreErr::StorageErr("Could not remove old snapshots".to_string()))?;
+
+        Ok(())
+    }
+
+    /// Appends under `append_lock` and the writer lease and, if MongoDB rejects part of the
+    /// batch, removes the part that was written so a failed append leaves nothing behind.
+    async fn insert_events(&self, stream: &str, expected_version: u32, events: Vec<EventEnvelope<Value>>) -> EventStoreResult<Vec<RecordedEvent<Value>>> {
+        let _guard = self.lock_appends().await?;
+        let first_position = self.allocate_positions(events.len()).await?;
+        let stored = events
+            .iter()
+            .enumerate()
+            .map(|(i, event)| {
+                StoredEvent::encode(first_position + i as u64, stream, expected_version + 1 + i as u32, event, self.codec.as_ref())
+            })
+            .collect::<EventStoreResult<Vec<StoredEvent>>>()?;
+
+        match self.events.insert_many(&stored, None).await {
+            Ok(_) => {
+                Ok(stored
+                    .into_iter()
+                    .zip(events)
+                    .map(|(stored, event)| RecordedEvent {
+                        position: stored.position,
+                        stream: stored.stream_id,
+                        version: stored.version,
+                        event,
+                    })
+                    .collect())
             }
-            Some(event) => {
-                event.version()
+            Err(err) => {
+                let positions = first_position..first_position + stored.len() as u64;
+                if self.events.delete_many(batch_query(stream, positions), None).await.is_err() {
+                    log::error!("Could not remove partially appended events of {}", stream);
+                }
+                if is_duplicate_key(&err, STREAM_VERSION_INDEX) {
+                    Err(EventStoreErr::ConcurrencyErr { stream: stream.to_string(), expected_version })
+                } else {
+                    Err(EventStoreErr::StorageErr("Could not insert events".to_string()))
+                }
             }
         }
     }
-    pub fn new(id: Guid) -> TodoEventColl {
-        TodoEventColl {
-            id,
-            events: vec![],
+
+    async fn find_events(&self, query: Document) -> EventStoreResult<Vec<StoredEvent>> {
+        let options = FindOptions::builder()
+            .sort(doc! { "stream_id": 1, "version": 1 })
+            .build();
+        let mut cursor = self.events
+            .find(query, options).await
+            .map_err(|_| EventStoreErr::StorageErr("Could not find events".to_string()))?;
+        let mut results: Vec<StoredEvent> = vec![];
+
+        while let Some(result) = cursor.next().await {
+            let event = result.map_err(|_| EventStoreErr::StorageErr("Could not deserialize".to_string()))?;
+            results.push(event);
+        };
+
+        Ok(results)
+    }
+
+    async fn read_events(&self, mut query: Document, after_position: u64, limit: usize) -> EventStoreResult<Vec<RecordedEvent<Value>>> {
+        query.insert("position", doc! { "$gt": after_position as i64 });
+        let options = FindOptions::builder()
+            .sort(doc! { "position": 1 })
+            .limit(limit as i64)
+            .build();
+        let mut cursor = self.events
+            .find(query, options).await
+            .map_err(|_| EventStoreErr::StorageErr("Could not read events".to_string()))?;
+        let mut results: Vec<RecordedEvent<Value>> = vec![];
+
+        while let Some(result) = cursor.next().await {
+            let stored = result.map_err(|_| EventStoreErr::StorageErr("Could not deserialize".to_string()))?;
+            results.push(stored.recorded()?);
+        };
+
+        Ok(results)
+    }
+
+    /// Version of the newest event of the stream, 0 if it has none.
+    async fn stream_version(&self, stream: &str) -> EventStoreResult<u32> {
+        let options = FindOneOptions::builder()
+            .sort(doc! { "version": -1 })
+            .build();
+
+        self.events
+            .find_one(doc! { "stream_id": stream }, options).await
+            .map(|stored| stored.map_or(0, |stored| stored.version))
+            .map_err(|_| EventStoreErr::StorageErr("Could not read stream version".to_string()))
+    }
+
+    /// Copies every not yet migrated document of the legacy collection into per-event
+    /// documents and returns how many streams were converted. Each stream is read back and
+    /// compared before its legacy document is marked as migrated; the legacy documents
+    /// themselves are left in place. Running it again skips streams that were already done,
+    /// so an interrupted migration can simply be restarted.
+    pub async fn migrate_legacy_streams(&self) -> EventStoreResult<usize> {
+        let mut cursor = self.legacy_streams
+            .find(doc! { "migrated": { "$ne": true } }, None).await
+            .map_err(|_| EventStoreErr::StorageErr("Could not list legacy events".to_string()))?;
+        let mut migrated = 0;
+
+        while let Some(result) = cursor.next().await {
+            let coll = result.map_err(|_| EventStoreErr::StorageErr("Could not deserialize".to_string()))?;
+            self.migrate_legacy_stream(&coll).await?;
+            migrated += 1;
         }
+
+        Ok(migrated)
     }
 
-    pub fn events(&self) -> Vec<TodoEvent> {
-        self.events.to_vec()
+    async fn migrate_legacy_stream(&self, coll: &TodoEventColl) -> EventStoreResult<()> {
+        let stream = TodoAggregate::stream_name(coll.id);
+        // A previous, interrupted run may already have copied the start of the stream.
+        let copied = self.stream_version(&stream).await?;
+        let missing = coll.events
+            .iter()
+            .filter(|envelope| envelope.version() > copied)
+            .map(EventEnvelope::to_raw)
+            .collect::<EventStoreResult<Vec<EventEnvelope<Value>>>>()?;
+        if !missing.is_empty() {
+            self.insert_events(&stream, copied, missing).await?;
+        }
+
+        let expected: Vec<Guid> = coll.events.iter().map(|envelope| envelope.event_id).collect();
+        let actual: Vec<Guid> = self.get(&stream).await?.iter().map(|envelope| envelope.event_id).collect();
+        let last_version = coll.events.last().map_or(0, |envelope| envelope.version());
+        if self.stream_version(&stream).await? != last_version || actual != expected {
+            return Err(EventStoreErr::StorageErr(format!("Migrated events for {} do not match the original stream", coll.id)));
+        }
+
+        self.legacy_streams
+            .update_one(doc! { "_id": coll.id.to_string() }, doc! { "$set": { "migrated": true } }, None).await
+            .map(|_| ())
+            .map_err(|_| EventStoreErr::StorageErr(format!("Could not mark {} as migrated", coll.id)))
     }
+}
 
-    pub fn add_event(mut self, valid_event: ValidTodoEvent) -> TodoEventColl {
-        self.events.push(valid_event.event());
-        self
+/// Matches the events of one append: its stream and the positions allocated to it. Event ids
+/// do not tell appends apart, since legacy events all share the empty id.
+fn batch_query(stream: &str, positions: Range<u64>) -> Document {
+    doc! {
+        "stream_id": stream,
+        "position": { "$gte": positions.start as i64, "$lt": positions.end as i64 }
     }
+}
 
-    pub fn to_agg(&self) -> TodoAggregate {
-        TodoAggregate::from_events(self.events.clone())
+const DUPLICATE_KEY: i32 = 11000;
+
+/// Whether `err` reports a duplicate key in the unique index named `index`.
+pub fn is_duplicate_key(err: &mongodb::error::Error, index: &str) -> bool {
+    let in_index = |code: i32, message: &str| code == DUPLICATE_KEY && names_index(message, index);
+    match err.kind.as_ref() {
+        ErrorKind::Write(WriteFailure::WriteError(write_err)) => { in_index(write_err.code, &write_err.message) }
+        ErrorKind::BulkWrite(failure) => {
+            failure.write_errors
+                .iter()
+                .flatten()
+                .any(|write_err| in_index(write_err.code, &write_err.message))
+        }
+        _ => { false }
     }
 }
 
-pub struct TodoEventCollRepo {
-    collection: Collection<TodoEventColl>,
+/// Whether a duplicate key error message, such as `E11000 duplicate key error collection:
+/// db.events index: position_1 dup key: { position: 7 }`, is about `index`.
+fn names_index(message: &str, index: &str) -> bool {
+    message.contains(&format!("index: {} ", index))
+}
+
+/// Matches the streams of `category`. Aggregate types are plain identifiers, so the prefix
+/// needs no escaping.
+fn category_query(category: &str) -> Document {
+    doc! { "stream_id": { "$regex": format!("^{}-", category) } }
+}
+
+/// Groups events sorted by stream and version into one `StoredStream` per stream.
+fn into_streams(events: Vec<StoredEvent>) -> EventStoreResult<Vec<StoredStream>> {
+    let mut streams: Vec<StoredStream> = vec![];
+    for stored in events {
+        let event = stored.decode()?;
+        match streams.last_mut() {
+            Some(stream) if stream.name == stored.stream_id => { stream.events.push(event) }
+            _ => { streams.push(StoredStream { name: stored.stream_id, events: vec![event] }) }
+        }
+    }
+
+    Ok(streams)
 }
 
-impl TodoEventCollRepo {
-    pub fn new(mongodb: &Client) -> TodoEventCollRepo {
-        TodoEventCollRepo {
-            collection: mongodb.database("rust-test").collection("todo-events")
+#[async_trait]
+impl EventStore for MongoEventStore {
+    async fn get(&self, stream: &str) -> EventStoreResult<Vec<EventEnvelope<Value>>> {
+        let query = doc! {
+            "stream_id": stream
+        };
+
+        match into_streams(self.find_events(query).await?)?.pop() {
+            None => { Err(EventStoreErr::NotFound(stream.to_string())) }
+            Some(stored) => { Ok(stored.events) }
         }
     }
 
-    pub async fn insert(&self, coll: &TodoEventColl) -> Result<(), String> {
-        match self.collection.insert_one(coll, None).await {
+    async fn list(&self, category: &str) -> EventStoreResult<Vec<StoredStream>> {
+        into_streams(self.find_events(category_query(category)).await?)
+    }
+
+    async fn append(&self, stream: &str, expected_version: u32, events: Vec<EventEnvelope<Value>>) -> EventStoreResult<Vec<RecordedEvent<Value>>> {
+        if expected_version > 0 {
+            // The unique index catches two writers racing for the same version, but not a
+            // caller that is ahead of the stream, which would otherwise leave a gap.
+            match self.stream_version(stream).await? {
+                0 => { return Err(EventStoreErr::NotFound(stream.to_string())); }
+                current if current != expected_version => {
+                    return Err(EventStoreErr::ConcurrencyErr { stream: stream.to_string(), expected_version });
+                }
+                _ => {}
+            }
+        }
+
+        self.insert_events(stream, expected_version, events).await
+    }
+
+    async fn read_all(&self, after_position: u64, limit: usize) -> EventStoreResult<Vec<RecordedEvent<Value>>> {
+        self.read_events(doc! {}, after_position, limit).await
+    }
+
+    async fn read_category(&self, category: &str, after_position: u64, limit: usize) -> EventStoreResult<Vec<RecordedEvent<Value>>> {
+        self.read_events(category_query(category), after_position, limit).await
+    }
+
+    async fn last_position(&self) -> EventStoreResult<u64> {
+        let options = FindOneOptions::builder()
+            .sort(doc! { "position": -1 })
+            .build();
+
+        self.events
+            .find_one(doc! { "position": { "$exists": true } }, options).await
+            .map(|stored| stored.map_or(0, |stored| stored.position))
+            .map_err(|_| EventStoreErr::StorageErr("Could not read the last position".to_string()))
+    }
+
+    async fn get_events_after(&self, stream: &str, version: u32) -> EventStoreResult<Vec<EventEnvelope<Value>>> {
+        let query = doc! {
+            "stream_id": stream,
+            "version": { "$gt": version as i64 }
+        };
+        let events = self.find_events(query).await?;
+        if events.is_empty() && self.stream_version(stream).await? == 0 {
+            return Err(EventStoreErr::NotFound(stream.to_string()));
+        }
+
+        events.iter().map(StoredEvent::decode).collect()
+    }
+
+    async fn get_snapshot(&self, stream: &str) -> EventStoreResult<Option<Snapshot>> {
+        let query = doc! {
+            "_id": stream
+        };
+
+        self.snapshots
+            .find_one(query, None).await
+            .map_err(|_| EventStoreErr::StorageErr("Could not find snapshot".to_string()))
+    }
+
+    async fn save_snapshot(&self, snapshot: &Snapshot) -> EventStoreResult<()> {
+        let query = doc! {
+            "_id": &snapshot.stream
+        };
+        let options = ReplaceOptions::builder()
+            .upsert(true)
+            .build();
+
+        match self.snapshots.replace_one(query, snapshot, options).await {
             Ok(_) => { Ok(()) }
-            Err(_) => { Err(format!("Could not insert events")) }
+            Err(_) => { Err(EventStoreErr::StorageErr("Could not save snapshot".to_string())) }
         }
     }
 
-    pub async fn get(&self, id: Guid) -> Result<TodoEventColl, String> {
+    async fn get_checkpoint(&self, name: &str) -> EventStoreResult<Option<u64>> {
         let query = doc! {
-            "_id": id.to_string()
+            "_id": name
         };
 
-        self.collection
+        self.checkpoints
             .find_one(query, None).await
-            .map_err(|_| "Could not find events".to_string())
-            .and_then(|result| match result {
-                None => { Err("Could not find events".to_string()) }
-                Some(events) => { Ok(events) }
-            })
+            .map(|checkpoint| checkpoint.map(|checkpoint| checkpoint.position))
+            .map_err(|_| EventStoreErr::StorageErr("Could not find checkpoint".to_string()))
     }
-    
-    pub async fn update(&self, coll: &TodoEventColl) -> Result<() , String> {
+
+    async fn save_checkpoint(&self, name: &str, position: u64) -> EventStoreResult<()> {
         let query = doc! {
-            "_id": coll.id.to_string()
+            "_id": name
+        };
+        let options = ReplaceOptions::builder()
+            .upsert(true)
+            .build();
+        let checkpoint = Checkpoint {
+            name: name.to_string(),
+            position,
         };
 
-        match self.collection.find_one_and_replace(query, coll, None).await {
-            Ok(_) => {Ok(())}
-            Err(_) => {Err("Could not update collection".to_string())}
+        match self.checkpoints.replace_one(query, checkpoint, options).await {
+            Ok(_) => { Ok(()) }
+            Err(_) => { Err(EventStoreErr::StorageErr("Could not save checkpoint".to_string())) }
         }
     }
-    
-    pub async fn list(&self) -> Result<Vec<TodoEventColl>, String> {
-        let mut cursor = self.collection
-            .find(None, None).await
-            .map_err(|_| "Could not list".to_string())?;
-        let mut results: Vec<TodoEventColl> = vec![];
-        
-        while let Some(result) = cursor.next().await {
-            let coll = result.map_err(|x| "Could not deserialize".to_string())?;
-            results.push(coll);
+
+    async fn delete_stream(&self, stream: &str, expected_version: u32) -> EventStoreResult<()> {
+        // Appends wait for the same turn, so none lands between the check and the delete.
+        let _guard = self.lock_appends().await?;
+        if self.stream_version(stream).await? != expected_version {
+            return Err(EventStoreErr::ConcurrencyErr { stream: stream.to_string(), expected_version });
+        }
+        self.events
+            .delete_many(doc! { "stream_id": stream }, None).await
+            .map_err(|_| EventStoreErr::StorageErr("Could not delete events".to_string()))?;
+        self.snapshots
+            .delete_one(doc! { "_id": stream }, None).await
+            .map(|_| ())
+            .map_err(|_| EventStoreErr::StorageErr("Could not delete snapshot".to_string()))
+    }
+}
+
+/// An event as the in-memory store keeps it, encoded like a persistent store would.
+struct EncodedEvent {
+    stream: String,
+    version: u32,
+    content_type: &'static str,
+    payload: Vec<u8>,
+}
+
+impl EncodedEvent {
+    fn decode(&self) -> EventStoreResult<EventEnvelope<Value>> {
+        codec::decode(self.content_type, &self.payload).map_err(EventStoreErr::StorageErr)
+    }
+}
+
+#[derive(Default)]
+struct MemoryLog {
+    /// Every appended event in position order; position n is stored at index n - 1. Events
+    /// of deleted streams leave `None` behind.
+    events: Vec<Option<EncodedEvent>>,
+    /// Indexes into `events` of each stream's events in version order.
+    streams: HashMap<String, Vec<usize>>,
+}
+
+impl MemoryLog {
+    /// An event of a stream; `streams` only points at events that are still stored.
+    fn event(&self, index: usize) -> &EncodedEvent {
+        self.events[index].as_ref().expect("deleted events are not indexed")
+    }
+
+    fn stream(&self, stream: &str) -> EventStoreResult<Vec<EventEnvelope<Value>>> {
+        match self.streams.get(stream) {
+            None => { Err(EventStoreErr::NotFound(stream.to_string())) }
+            Some(indexes) => { indexes.iter().map(|i| self.event(*i).decode()).collect() }
+        }
+    }
+
+    fn read(&self, category: Option<&str>, after_position: u64, limit: usize) -> EventStoreResult<Vec<RecordedEvent<Value>>> {
+        let first_index = after_position.min(self.events.len() as u64) as usize;
+
+        self.events[first_index..]
+            .iter()
+            .enumerate()
+            .filter_map(|(i, encoded)| encoded.as_ref().map(|encoded| (i, encoded)))
+            .filter(|(_, encoded)| category.is_none_or(|category| in_category(&encoded.stream, category)))
+            .take(limit)
+            .map(|(i, encoded)| Ok(RecordedEvent {
+                position: (first_index + i) as u64 + 1,
+                stream: encoded.stream.clone(),
+                version: encoded.version,
+                event: encoded.decode()?,
+            }))
+            .collect()
+    }
+}
+
+/// Keeps every event stream in process memory. Nothing survives a restart, so this is meant
+/// for tests and local demos that should run without a MongoDB instance.
+pub struct InMemoryEventStore {
+    codec: Arc<dyn EventCodec>,
+    log: RwLock<MemoryLog>,
+    snapshots: RwLock<HashMap<String, Snapshot>>,
+    checkpoints: RwLock<HashMap<String, u64>>,
+}
+
+impl Default for InMemoryEventStore {
+    fn default() -> InMemoryEventStore {
+        InMemoryEventStore::with_codec(Arc::new(JsonCodec))
+    }
+}
+
+impl InMemoryEventStore {
+    pub fn with_codec(codec: Arc<dyn EventCodec>) -> InMemoryEventStore {
+        InMemoryEventStore {
+            codec,
+            log: RwLock::new(MemoryLog::default()),
+            snapshots: RwLock::new(HashMap::new()),
+            checkpoints: RwLock::new(HashMap::new()),
+        }
+    }
+}
+
+#[async_trait]
+impl EventStore for InMemoryEventStore {
+    async fn get(&self, stream: &str) -> EventStoreResult<Vec<EventEnvelope<Value>>> {
+        let log = self.log
+            .read()
+            .map_err(|_| EventStoreErr::StorageErr("Could not find events".to_string()))?;
+
+        log.stream(stream)
+    }
+
+    async fn list(&self, category: &str) -> EventStoreResult<Vec<StoredStream>> {
+        let log = self.log
+            .read()
+            .map_err(|_| EventStoreErr::StorageErr("Could not list".to_string()))?;
+
+        log.streams
+            .keys()
+            .filter(|name| in_category(name, category))
+            .map(|name| Ok(StoredStream { name: name.clone(), events: log.stream(name)? }))
+            .collect()
+    }
+
+    async fn append(&self, stream: &str, expected_version: u32, events: Vec<EventEnvelope<Value>>) -> EventStoreResult<Vec<RecordedEvent<Value>>> {
+        let mut log = self.log
+            .write()
+            .map_err(|_| EventStoreErr::StorageErr("Could not update collection".to_string()))?;
+        let version = match log.streams.get(stream) {
+            None if expected_version == 0 => { 0 }
+            None => { return Err(EventStoreErr::NotFound(stream.to_string())); }
+            Some(indexes) => { indexes.last().map_or(0, |i| log.event(*i).version) }
         };
-        
-        Ok(results)
+        if version != expected_version {
+            return Err(EventStoreErr::ConcurrencyErr { stream: stream.to_string(), expected_version });
+        }
+
+        let mut encoded = vec![];
+        for (i, event) in events.iter().enumerate() {
+            let payload = self.codec
+                .encode(event)
+                .map_err(EventStoreErr::StorageErr)?;
+            encoded.push(EncodedEvent {
+                stream: stream.to_string(),
+                version: expected_version + 1 + i as u32,
+                content_type: self.codec.content_type(),
+                payload,
+            });
+        }
+
+        let first_index = log.events.len();
+        log.events.extend(encoded.into_iter().map(Some));
+        log.streams
+            .entry(stream.to_string())
+            .or_default()
+            .extend(first_index..first_index + events.len());
+
+        Ok(events
+            .into_iter()
+            .enumerate()
+            .map(|(i, event)| RecordedEvent {
+                position: (first_index + i) as u64 + 1,
+                stream: stream.to_string(),
+                version: expected_version + 1 + i as u32,
+                event,
+            })
+            .collect())
+    }
+
+    async fn read_all(&self, after_position: u64, limit: usize) -> EventStoreResult<Vec<RecordedEvent<Value>>> {
+        self.log
+            .read()
+            .map_err(|_| EventStoreErr::StorageErr("Could not read events".to_string()))?
+            .read(None, after_position, limit)
+    }
+
+    async fn read_category(&self, category: &str, after_position: u64, limit: usize) -> EventStoreResult<Vec<RecordedEvent<Value>>> {
+        self.log
+            .read()
+            .map_err(|_| EventStoreErr::StorageErr("Could not read events".to_string()))?
+            .read(Some(category), after_position, limit)
+    }
+
+    async fn last_position(&self) -> EventStoreResult<u64> {
+        let log = self.log
+            .read()
+            .map_err(|_| EventStoreErr::StorageErr("Could not read events".to_string()))?;
+
+        Ok(log.events.len() as u64)
+    }
+
+    async fn get_events_after(&self, stream: &str, version: u32) -> EventStoreResult<Vec<EventEnvelope<Value>>> {
+        let log = self.log
+            .read()
+            .map_err(|_| EventStoreErr::StorageErr("Could not find events".to_string()))?;
+        let indexes = log.streams
+            .get(stream)
+            .ok_or_else(|| EventStoreErr::NotFound(stream.to_string()))?;
+
+        indexes
+            .iter()
+            .map(|i| log.event(*i))
+            .filter(|encoded| encoded.version > version)
+            .map(EncodedEvent::decode)
+            .collect()
+    }
+
+    async fn get_snapshot(&self, stream: &str) -> EventStoreResult<Option<Snapshot>> {
+        let snapshots = self.snapshots
+            .read()
+            .map_err(|_| EventStoreErr::StorageErr("Could not find snapshot".to_string()))?;
+
+        Ok(snapshots.get(stream).cloned())
     }
-}
\ No newline at end of file
+
+    async fn save_snapshot(&self, snapshot: &Snapshot) -> EventStoreResult<()> {
+        let mut snapshots = self.snapshots
+            .write()
+            .map_err(|_| EventStoreErr::StorageErr("Could not save snapshot".to_string()))?;
+        snapshots.insert(snapshot.stream.clone(), snapshot.clone());
+
+        Ok(())
+    }
+
+    async fn get_checkpoint(&self, name: &str) -> EventStoreResult<Option<u64>> {
+        let checkpoints = self.checkpoints
+            .read()
+            .map_err(|_| EventStoreErr::StorageErr("Could not find checkpoint".to_string()))?;
+
+        Ok(checkpoints.get(name).copied())
+    }
+
+    async fn save_checkpoint(&self, name: &str, position: u64) -> EventStoreResult<()> {
+        let mut checkpoints = self.checkpoints
+            .write()
+            .map_err(|_| EventStoreErr::StorageErr("Could not save checkpoint".to_string()))?;
+        checkpoints.insert(name.to_string(), position);
+
+        Ok(())
+    }
+
+    async fn delete_stream(&self, stream: &str, expected_version: u32) -> EventStoreResult<()> {
+        let mut log = self.log
+            .write()
+            .map_err(|_| EventStoreErr::StorageErr("Could not delete events".to_string()))?;
+        let version = log.streams
+            .get(stream)
+            .and_then(|indexes| indexes.last())
+            .map_or(0, |i| log.event(*i).version);
+        if version != expected_version {
+            return Err(EventStoreErr::ConcurrencyErr { stream: stream.to_string(), expected_version });
+        }
+        for index in log.streams.remove(stream).unwrap_or_default() {
+            log.events[index] = None;
+        }
+        self.snapshots
+            .write()
+            .map_err(|_| EventStoreErr::StorageErr("Could not delete snapshot".to_string()))?
+            .remove(stream);
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::Value;
+    use crate::guid::Guid;
+    use crate::services::aggregate::{Aggregate, TodoAggregate, TodoEvent};
+    use crate::services::codec::JsonCodec;
+    use crate::services::event_store::{batch_query, names_index, EventEnvelope, EventMetadata, EventStore, EventStoreErr, InMemoryEventStore, StoredEvent, ID_INDEX, STREAM_VERSION_INDEX};
+
+    fn envelope(event: TodoEvent) -> EventEnvelope<Value> {
+        EventEnvelope::new(event, &EventMetadata::default()).to_raw().unwrap()
+    }
+
+    #[rocket::async_test]
+    async fn append_rejects_a_stale_expected_version() {
+        let store = InMemoryEventStore::default();
+        let id = Guid::new();
+        let stream = TodoAggregate::stream_name(id);
+        store.append(&stream, 0, vec![envelope(TodoEvent::Create { name: "a".to_string(), id })]).await.unwrap();
+        store.append(&stream, 1, vec![envelope(TodoEvent::ChangeName { new_name: "b".to_string(), version: 2 })]).await.unwrap();
+
+        let stale = store.append(&stream, 1, vec![envelope(TodoEvent::ChangeName { new_name: "c".to_string(), version: 2 })]).await;
+        assert!(matches!(stale, Err(EventStoreErr::ConcurrencyErr { expected_version: 1, .. })));
+        let again = store.append(&stream, 0, vec![envelope(TodoEvent::Create { name: "d".to_string(), id })]).await;
+        assert!(matches!(again, Err(EventStoreErr::ConcurrencyErr { expected_version: 0, .. })));
+
+        assert_eq!(store.get(&stream).await.unwrap().len(), 2);
+        assert_eq!(store.last_position().await.unwrap(), 2);
+    }
+
+    #[test]
+    fn a_failed_append_only_rolls_back_its_own_events() {
+        // Migrated legacy events all carry the empty event id.
+        let legacy = |position: u64, stream: &str, version: u32| {
+            let mut event = envelope(TodoEvent::ChangeName { new_name: "a".to_string(), version });
+            event.event_id = Guid::empty();
+            StoredEvent::encode(position, stream, version, &event, &JsonCodec).unwrap()
+        };
+        let (migrated, failed) = (TodoAggregate::stream_name(Guid::new()), TodoAggregate::stream_name(Guid::new()));
+        let stored = [legacy(1, &migrated, 1), legacy(2, &migrated, 2), legacy(3, &failed, 1), legacy(4, &failed, 2)];
+
+        // The second stream's append was given positions 3 and 4 before it failed.
+        let query = batch_query(&failed, 3..5);
+        let positions = query.get_document("position").unwrap();
+        let rolled_back: Vec<u64> = stored
+            .iter()
+            .filter(|stored| {
+                query.get_str("stream_id").unwrap() == stored.stream_id
+                    && positions.get_i64("$gte").unwrap() <= stored.position as i64
+                    && (stored.position as i64) < positions.get_i64("$lt").unwrap()
+            })
+            .map(|stored| stored.position)
+            .collect();
+        assert_eq!(rolled_back, vec![3, 4]);
+        assert!(!query.contains_key("event_id"));
+    }
+
+    #[test]
+    fn only_version_collisions_are_concurrency_errors() {
+        let version = "E11000 duplicate key error collection: todo.todo-stream-events index: stream_id_1_version_1 dup key: { stream_id: \"todo-1\", version: 2 }";
+        let position = "E11000 duplicate key error collection: todo.todo-stream-events index: position_1 dup key: { position: 7 }";
+
+        assert!(names_index(version, STREAM_VERSION_INDEX));
+        assert!(!names_index(position, STREAM_VERSION_INDEX));
+    }
+
+    #[test]
+    fn recognises_a_writer_lease_held_elsewhere() {
+        let lease = "E11000 duplicate key error collection: todo.todo-counters index: _id_ dup key: { _id: \"writer\" }";
+
+        assert!(names_index(lease, ID_INDEX));
+        assert!(!names_index(lease, STREAM_VERSION_INDEX));
+    }
+}
@@ -0,0 +1,40 @@
+use rocket::tokio::sync::broadcast::{self, Receiver, Sender};
+use serde_derive::Serialize;
+use crate::routes::todo::Todo;
+use crate::services::aggregate::TodoEvent;
+use crate::services::event_store::EventEnvelope;
+
+const CHANNEL_CAPACITY: usize = 256;
+
+/// A persisted event together with the todo it produced. `position` is the event's place in
+/// the event store's global log.
+#[derive(Debug, Serialize, Clone)]
+pub struct TodoChange {
+    pub position: u64,
+    pub event: EventEnvelope<TodoEvent>,
+    pub todo: Todo,
+}
+
+/// Fans newly persisted events out to live subscribers. It keeps no history of its own;
+/// subscribers that missed changes catch up from the event store by position.
+pub struct ChangeFeed {
+    sender: Sender<TodoChange>,
+}
+
+impl ChangeFeed {
+    pub fn new() -> ChangeFeed {
+        let (sender, _) = broadcast::channel(CHANNEL_CAPACITY);
+        ChangeFeed {
+            sender
+        }
+    }
+
+    pub fn publish(&self, change: TodoChange) {
+        // An error only means nobody is listening right now.
+        let _ = self.sender.send(change);
+    }
+
+    pub fn subscribe(&self) -> Receiver<TodoChange> {
+        self.sender.subscribe()
+    }
+}
@@ -0,0 +1,269 @@
+use serde_derive::{Deserialize, Serialize};
+use crate::guid::Guid;
+use crate::services::aggregate::{Aggregate, AggregateErr, DomainError, DomainEvent};
+
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+#[serde(tag = "type")]
+pub enum TodoListEvent {
+    Create { name: String, id: Guid },
+    Rename { new_name: String, version: u32 },
+    Archive { version: u32 },
+    AddTodo { todo: Guid, version: u32 },
+    RemoveTodo { todo: Guid, version: u32 },
+}
+
+impl DomainEvent for TodoListEvent {
+    fn version(&self) -> u32 {
+        match self {
+            TodoListEvent::Create { .. } => { 1 }
+            TodoListEvent::Rename { version, .. } => { version.to_owned() }
+            TodoListEvent::Archive { version } => { version.to_owned() }
+            TodoListEvent::AddTodo { version, .. } => { version.to_owned() }
+            TodoListEvent::RemoveTodo { version, .. } => { version.to_owned() }
+        }
+    }
+}
+
+/// What a client asks a list to do. Membership only changes while the service moves a todo,
+/// which records the move on the todo as well.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+#[serde(tag = "type")]
+pub enum TodoListCommand {
+    #[serde(skip_deserializing)]
+    CreateList { id: Guid, name: String },
+    RenameList { name: String },
+    ArchiveList,
+    #[serde(skip_deserializing)]
+    AddTodo { todo: Guid },
+    #[serde(skip_deserializing)]
+    RemoveTodo { todo: Guid },
+}
+
+#[derive(Clone)]
+pub struct ValidTodoListEvent {
+    event: TodoListEvent,
+}
+
+impl From<ValidTodoListEvent> for TodoListEvent {
+    fn from(valid_event: ValidTodoListEvent) -> TodoListEvent {
+        valid_event.event
+    }
+}
+
+/// A named group of todos. Archived lists keep their todos but take no new ones.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct TodoList {
+    pub id: Guid,
+    pub name: String,
+    /// Members in the order they joined the list.
+    pub todos: Vec<Guid>,
+    pub archived: bool,
+    version: u32,
+}
+
+impl TodoList {
+    pub fn new() -> TodoList {
+        TodoList {
+            id: Guid::empty(),
+            name: "".to_string(),
+            todos: vec![],
+            archived: false,
+            version: 0,
+        }
+    }
+}
+
+impl Aggregate for TodoList {
+    // Not `todo-list`, which would put list streams in the `todo` category.
+    const TYPE: &'static str = "list";
+    type Event = TodoListEvent;
+    type ValidEvent = ValidTodoListEvent;
+    type Command = TodoListCommand;
+
+    fn version(&self) -> u32 {
+        self.version
+    }
+
+    fn decide(&self, command: TodoListCommand) -> Result<Vec<TodoListEvent>, DomainError> {
+        let version = self.version + 1;
+
+        let event = match command {
+            TodoListCommand::CreateList { id, name } => {
+                if self.version > 0 {
+                    return Err(DomainError::AlreadyExists);
+                }
+                if name.trim().is_empty() {
+                    return Err(DomainError::EmptyName);
+                }
+                TodoListEvent::Create { name, id }
+            }
+            TodoListCommand::RenameList { name } => {
+                if self.archived {
+                    return Err(DomainError::Archived);
+                }
+                if name.trim().is_empty() {
+                    return Err(DomainError::EmptyName);
+                }
+                if name == self.name {
+                    return Err(DomainError::NameUnchanged);
+                }
+                TodoListEvent::Rename { new_name: name, version }
+            }
+            TodoListCommand::ArchiveList => {
+                if self.archived {
+                    return Err(DomainError::AlreadyArchived);
+                }
+                TodoListEvent::Archive { version }
+            }
+            TodoListCommand::AddTodo { todo } => {
+                if self.archived {
+                    return Err(DomainError::Archived);
+                }
+                if self.todos.contains(&todo) {
+                    return Err(DomainError::AlreadyInList);
+                }
+                TodoListEvent::AddTodo { todo, version }
+            }
+            // Todos can still be moved out of an archived list.
+            TodoListCommand::RemoveTodo { todo } => {
+                if !self.todos.contains(&todo) {
+                    return Err(DomainError::NotInList);
+                }
+                TodoListEvent::RemoveTodo { todo, version }
+            }
+        };
+
+        Ok(vec![event])
+    }
+
+    fn try_apply(&self, event: TodoListEvent) -> Result<ValidTodoListEvent, AggregateErr> {
+        // Only `Create` starts a stream.
+        let starts = matches!(event, TodoListEvent::Create { .. });
+        if event.version() != self.version + 1 || (self.version == 0) != starts {
+            Err(AggregateErr::ConcurrencyErr)
+        } else {
+            Ok(ValidTodoListEvent { event })
+        }
+    }
+
+    fn apply(mut self, valid_event: &ValidTodoListEvent) -> TodoList {
+        let event = &valid_event.event;
+        self.version = event.version();
+        match event {
+            TodoListEvent::Create { name, id } => {
+                self.name = name.clone();
+                self.id = *id;
+            }
+            TodoListEvent::Rename { new_name, .. } => {
+                self.name = new_name.clone();
+            }
+            TodoListEvent::Archive { .. } => {
+                self.archived = true;
+            }
+            TodoListEvent::AddTodo { todo, .. } => {
+                self.todos.push(*todo);
+            }
+            TodoListEvent::RemoveTodo { todo, .. } => {
+                self.todos.retain(|member| member != todo);
+            }
+        };
+
+        self
+    }
+
+    fn from_events(events: Vec<TodoListEvent>) -> TodoList {
+        TodoList::from_snapshot(TodoList::new(), events)
+    }
+
+    fn from_snapshot(state: TodoList, events: Vec<TodoListEvent>) -> TodoList {
+        events.into_iter().fold(state, |list, e| {
+            let valid_event = list.try_apply(e).unwrap();
+            list.apply(&valid_event)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::guid::Guid;
+    use crate::services::aggregate::{AggregateErr, DomainError};
+    use crate::services::list::{TodoList, TodoListCommand, TodoListEvent};
+    use crate::services::scenario::Scenario;
+
+    fn created(id: Guid) -> TodoListEvent {
+        TodoListEvent::Create { name: "groceries".to_string(), id }
+    }
+
+    #[test]
+    fn create_command_requires_a_new_list_with_a_name() {
+        let id = Guid::new();
+        let list = Scenario::<TodoList>::given_nothing()
+            .when(TodoListCommand::CreateList { id, name: "groceries".to_string() })
+            .then_events(vec![created(id)]);
+        assert_eq!((list.id, list.name.as_str(), list.archived), (id, "groceries", false));
+
+        Scenario::<TodoList>::given_nothing()
+            .when(TodoListCommand::CreateList { id, name: " ".to_string() })
+            .then_rejected(DomainError::EmptyName);
+        Scenario::<TodoList>::given(vec![created(id)])
+            .when(TodoListCommand::CreateList { id, name: "again".to_string() })
+            .then_rejected(DomainError::AlreadyExists);
+    }
+
+    #[test]
+    fn membership_follows_add_and_remove() {
+        let (first, second) = (Guid::new(), Guid::new());
+        let list = Scenario::<TodoList>::given(vec![
+            created(Guid::new()),
+            TodoListEvent::AddTodo { todo: first, version: 2 },
+        ])
+            .when(TodoListCommand::AddTodo { todo: second })
+            .then_events(vec![TodoListEvent::AddTodo { todo: second, version: 3 }]);
+        assert_eq!(list.todos, vec![first, second]);
+
+        let given = vec![created(Guid::new()), TodoListEvent::AddTodo { todo: first, version: 2 }];
+        Scenario::<TodoList>::given(given.clone())
+            .when(TodoListCommand::AddTodo { todo: first })
+            .then_rejected(DomainError::AlreadyInList);
+        Scenario::<TodoList>::given(given.clone())
+            .when(TodoListCommand::RemoveTodo { todo: second })
+            .then_rejected(DomainError::NotInList);
+        let list = Scenario::<TodoList>::given(given)
+            .when(TodoListCommand::RemoveTodo { todo: first })
+            .then_events(vec![TodoListEvent::RemoveTodo { todo: first, version: 3 }]);
+        assert!(list.todos.is_empty());
+    }
+
+    #[test]
+    fn archived_lists_only_let_todos_leave() {
+        let todo = Guid::new();
+        let archived = vec![
+            created(Guid::new()),
+            TodoListEvent::AddTodo { todo, version: 2 },
+            TodoListEvent::Archive { version: 3 },
+        ];
+
+        Scenario::<TodoList>::given(archived.clone())
+            .when(TodoListCommand::AddTodo { todo: Guid::new() })
+            .then_rejected(DomainError::Archived);
+        Scenario::<TodoList>::given(archived.clone())
+            .when(TodoListCommand::RenameList { name: "old groceries".to_string() })
+            .then_rejected(DomainError::Archived);
+        Scenario::<TodoList>::given(archived.clone())
+            .when(TodoListCommand::ArchiveList)
+            .then_rejected(DomainError::AlreadyArchived);
+        Scenario::<TodoList>::given(archived)
+            .when(TodoListCommand::RemoveTodo { todo })
+            .then_events(vec![TodoListEvent::RemoveTodo { todo, version: 4 }]);
+    }
+
+    #[test]
+    fn events_must_start_with_create_and_carry_the_next_version() {
+        Scenario::<TodoList>::given_nothing()
+            .when_event(TodoListEvent::Archive { version: 1 })
+            .then_error(AggregateErr::ConcurrencyErr);
+        Scenario::<TodoList>::given(vec![created(Guid::new())])
+            .when_event(TodoListEvent::Archive { version: 3 })
+            .then_error(AggregateErr::ConcurrencyErr);
+    }
+}
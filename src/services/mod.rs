@@ -5,11 +5,26 @@ pub mod todo;
 pub mod data;
 pub mod aggregate;
 pub mod event_store;
+pub mod projection;
+pub mod feed;
+pub mod sqlite;
+pub mod subscription;
+pub mod upcast;
+pub mod codec;
+pub mod repository;
+pub mod clock;
+pub mod list;
+pub mod trash;
+#[cfg(test)]
+pub mod scenario;
+#[cfg(test)]
+mod aggregate_properties;
 
-pub async fn create_mongo_client() -> Client {
-    let client_options = ClientOptions::parse("mongodb://localhost:27017")
+pub async fn create_mongo_client(uri: &str) -> Result<Client, String> {
+    let client_options = ClientOptions::parse(uri)
         .await
-        .expect("Could not parse client options");
-    
-    Client::with_options(client_options).unwrap()
+        .map_err(|err| format!("Could not parse todo.mongo.uri: {}", err))?;
+
+    Client::with_options(client_options)
+        .map_err(|err| format!("Could not create MongoDB client: {}", err))
 }
\ No newline at end of file
@@ -0,0 +1,269 @@
+use std::collections::HashSet;
+use std::sync::Arc;
+use serde_json::Value;
+use crate::guid::Guid;
+use crate::routes::todo::{Status, Todo};
+use crate::services::aggregate::{TodoAggregate, TodoEvent};
+use crate::services::data::{DataAccessErr, DataAccessResult, TodoRepository};
+use crate::services::event_store::{EventEnvelope, RecordedEvent};
+use crate::services::repository::EventSourcedRepository;
+use crate::services::subscription::EventHandler;
+
+/// Name of the subscriber that keeps the read model up to date.
+pub const PROJECTION_SUBSCRIBER: &str = "todo-projection";
+/// How many events a rebuild reads at once while catching up.
+const CATCH_UP_BATCH_SIZE: usize = 100;
+
+/// Keeps the flattened `Todo` read model in step with the events appended to the event store.
+/// Every event is projected by the `PROJECTION_SUBSCRIBER`, which retries until the read model
+/// accepts it. The service also projects its own events right away so they can be read back
+/// at once; projecting an event twice changes nothing.
+#[derive(Clone)]
+pub struct TodoProjection {
+    todo_repo: Arc<dyn TodoRepository>,
+    todos: EventSourcedRepository<TodoAggregate>,
+}
+
+impl TodoProjection {
+    pub fn new(todo_repo: Arc<dyn TodoRepository>, todos: EventSourcedRepository<TodoAggregate>) -> TodoProjection {
+        TodoProjection {
+            todo_repo,
+            todos,
+        }
+    }
+
+    /// Folds newly appended `events` of stream `id` into the stored todo. Events the stored todo
+    /// already has are skipped. If the stored todo is missing or not at the version right
+    /// before the first event, for example because an earlier projection failed, the todo is
+    /// projected again from its whole stream instead. The service and the subscriber project
+    /// the same events, so the result is only stored if it is newer than the stored todo.
+    pub async fn project(&self, id: Guid, events: &[EventEnvelope<TodoEvent>]) -> DataAccessResult<()> {
+        let (first_version, last_version) = match (events.first(), events.last()) {
+            (Some(first), Some(last)) => { (first.event.version(), last.event.version()) }
+            _ => { return Ok(()); }
+        };
+        match self.todo_repo.get_by_id(id).await.ok() {
+            Some(todo) if todo.version >= last_version => { Ok(()) }
+            Some(todo) if todo.version + 1 == first_version => {
+                match fold(Some(todo), events) {
+                    None => { self.todo_repo.delete(id).await }
+                    Some(todo) => { self.todo_repo.upsert_if_newer(todo).await.map(|_| ()) }
+                }
+            }
+            _ => { self.project_stream(id).await }
+        }
+    }
+
+    /// Regenerates the read model out of every stream in the event store. Todos are replaced
+    /// one by one, so readers keep seeing a complete read model while it runs, and events
+    /// appended in the meantime are projected again once every stream is done.
+    pub async fn rebuild(&self) -> DataAccessResult<usize> {
+        let event_repo = self.todos.event_store();
+        let mut position = event_repo
+            .last_position().await
+            .map_err(|_| DataAccessErr::new("Could not read the last position"))?;
+        let streams = self.todos
+            .list().await
+            .map_err(|_| DataAccessErr::new("Could not list events"))?;
+
+        let mut existing = HashSet::new();
+        let mut projected = 0;
+        for (id, envelopes) in streams {
+            existing.insert(id);
+            match fold(None, &envelopes) {
+                None => { self.todo_repo.delete(id).await?; }
+                Some(todo) => {
+                    self.todo_repo.upsert(todo).await?;
+                    projected += 1;
+                }
+            }
+        }
+
+        loop {
+            let recorded = self.todos
+                .read(position, CATCH_UP_BATCH_SIZE).await
+                .map_err(|_| DataAccessErr::new("Could not read events"))?;
+            let Some(last) = recorded.last() else { break; };
+            position = last.position;
+            for recorded in recorded {
+                let id = EventSourcedRepository::<TodoAggregate>::id_of(&recorded.stream)
+                    .map_err(|err| DataAccessErr::new(&err.to_string()))?;
+                existing.insert(id);
+                self.project(id, &[recorded.event]).await?;
+            }
+        }
+
+        // Todos whose streams are gone from the event store.
+        let mut stored = self.todo_repo.list().await?;
+        stored.extend(self.todo_repo.list_deleted().await?);
+        for todo in stored {
+            if !existing.contains(&todo.id) {
+                self.todo_repo.delete(todo.id).await?;
+            }
+        }
+
+        Ok(projected)
+    }
+
+    async fn project_stream(&self, id: Guid) -> DataAccessResult<()> {
+        let events = self.todos
+            .history(id).await
+            .map_err(|_| DataAccessErr::new("Could not find events"))?;
+
+        match fold(None, &events) {
+            None => { self.todo_repo.delete(id).await }
+            Some(todo) => { self.todo_repo.upsert_if_newer(todo).await.map(|_| ()) }
+        }
+    }
+}
+
+#[async_trait]
+impl EventHandler for TodoProjection {
+    async fn handle(&self, event: &RecordedEvent<Value>) -> Result<(), String> {
+        // Other aggregate types share the log.
+        let Ok(id) = EventSourcedRepository::<TodoAggregate>::id_of(&event.stream) else {
+            return Ok(());
+        };
+        let event = event.event
+            .clone()
+            .parse::<TodoEvent>()
+            .map_err(|err| err.to_string())?;
+
+        self.project(id, &[event]).await.map_err(|err| err.to_string())
+    }
+}
+
+/// Deleted todos are kept with the time of their deletion, which a `Restore` clears again.
+/// Events without a `Create` before them fold to `None`.
+fn fold(todo: Option<Todo>, envelopes: &[EventEnvelope<TodoEvent>]) -> Option<Todo> {
+    envelopes.iter().fold(todo, |todo, envelope| {
+        let event = &envelope.event;
+        match event {
+            TodoEvent::Create { name, id } => {
+                Some(Todo {
+                    id: *id,
+                    status: Status::Incomplete,
+                    name: name.clone(),
+                    due: None,
+                    overdue: false,
+                    list: None,
+                    parent: None,
+                    version: event.version(),
+                    deleted_at: None,
+                })
+            }
+            TodoEvent::ChangeName { new_name, version } => {
+                todo.map(|todo| Todo { name: new_name.clone(), version: *version, ..todo })
+            }
+            TodoEvent::ChangeStatus { status, version } => {
+                todo.map(|todo| Todo { status: status.clone(), version: *version, ..todo })
+            }
+            TodoEvent::SetDueDate { due, version } => {
+                todo.map(|todo| Todo { due: Some(*due), version: *version, ..todo })
+            }
+            TodoEvent::ClearDueDate { version } => {
+                todo.map(|todo| Todo { due: None, version: *version, ..todo })
+            }
+            TodoEvent::ChangeList { list, version } => {
+                todo.map(|todo| Todo { list: *list, version: *version, ..todo })
+            }
+            TodoEvent::AttachToParent { parent, version } => {
+                todo.map(|todo| Todo { parent: Some(*parent), version: *version, ..todo })
+            }
+            TodoEvent::DetachFromParent { version } => {
+                todo.map(|todo| Todo { parent: None, version: *version, ..todo })
+            }
+            TodoEvent::Delete { version } => {
+                todo.map(|todo| Todo { deleted_at: Some(envelope.recorded_at), version: *version, ..todo })
+            }
+            TodoEvent::Restore { version } => {
+                todo.map(|todo| Todo { deleted_at: None, version: *version, ..todo })
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use serde_json::Value;
+    use crate::guid::Guid;
+    use crate::routes::todo::{Status, Todo};
+    use crate::services::aggregate::{Aggregate, TodoAggregate, TodoEvent};
+    use crate::services::data::{InMemoryTodoRepository, TodoRepository};
+    use crate::services::event_store::{EventEnvelope, EventMetadata, EventStore, InMemoryEventStore};
+    use crate::services::projection::TodoProjection;
+    use crate::services::repository::EventSourcedRepository;
+    use crate::services::subscription::EventHandler;
+
+    fn envelope(event: TodoEvent) -> EventEnvelope<Value> {
+        EventEnvelope::new(event, &EventMetadata::default()).to_raw().unwrap()
+    }
+
+    fn projection(event_repo: Arc<dyn EventStore>, todo_repo: Arc<dyn TodoRepository>) -> TodoProjection {
+        TodoProjection::new(todo_repo, EventSourcedRepository::new(event_repo, 0))
+    }
+
+    fn todo(id: Guid, name: &str, version: u32) -> Todo {
+        Todo {
+            id,
+            status: Status::Incomplete,
+            name: name.to_string(),
+            due: None,
+            overdue: false,
+            list: None,
+            parent: None,
+            version,
+            deleted_at: None,
+        }
+    }
+
+    #[rocket::async_test]
+    async fn rebuild_replaces_the_read_model_with_the_event_store() {
+        let event_repo: Arc<dyn EventStore> = Arc::new(InMemoryEventStore::default());
+        let todo_repo: Arc<dyn TodoRepository> = Arc::new(InMemoryTodoRepository::new());
+        let (kept, deleted, unknown) = (Guid::new(), Guid::new(), Guid::new());
+        event_repo.append(&TodoAggregate::stream_name(kept), 0, vec![
+            envelope(TodoEvent::Create { name: "a".to_string(), id: kept }),
+            envelope(TodoEvent::ChangeName { new_name: "b".to_string(), version: 2 }),
+        ]).await.unwrap();
+        event_repo.append(&TodoAggregate::stream_name(deleted), 0, vec![
+            envelope(TodoEvent::Create { name: "c".to_string(), id: deleted }),
+            envelope(TodoEvent::Delete { version: 2 }),
+        ]).await.unwrap();
+        for stale in [todo(kept, "a", 1), todo(deleted, "c", 1), todo(unknown, "d", 1)] {
+            todo_repo.upsert(stale).await.unwrap();
+        }
+
+        assert_eq!(projection(event_repo, todo_repo.clone()).rebuild().await.unwrap(), 2);
+        assert_eq!(todo_repo.list().await.unwrap(), vec![todo(kept, "b", 2)]);
+        let trash = todo_repo.list_deleted().await.unwrap();
+        assert_eq!((trash.len(), trash[0].id, trash[0].version), (1, deleted, 2));
+    }
+
+    #[rocket::async_test]
+    async fn deleted_todos_stay_in_the_read_model_until_restored() {
+        let event_repo: Arc<dyn EventStore> = Arc::new(InMemoryEventStore::default());
+        let todo_repo: Arc<dyn TodoRepository> = Arc::new(InMemoryTodoRepository::new());
+        let projection = projection(event_repo.clone(), todo_repo.clone());
+        let id = Guid::new();
+        let stream = TodoAggregate::stream_name(id);
+        let events = [
+            TodoEvent::Create { name: "a".to_string(), id },
+            TodoEvent::Delete { version: 2 },
+            TodoEvent::Restore { version: 3 },
+        ];
+        let mut recorded = vec![];
+        for (version, event) in events.into_iter().enumerate() {
+            recorded.extend(event_repo.append(&stream, version as u32, vec![envelope(event)]).await.unwrap());
+            let last = recorded.last().unwrap();
+            projection.handle(last).await.unwrap();
+            let deleted_at = (version == 1).then_some(last.event.recorded_at);
+            assert_eq!(todo_repo.get_by_id(id).await.unwrap(), Todo { deleted_at, ..todo(id, "a", version as u32 + 1) });
+        }
+
+        // Handling an event again, as after a restart, leaves the todo as it is.
+        projection.handle(&recorded[0]).await.unwrap();
+        assert_eq!(todo_repo.get_by_id(id).await.unwrap(), todo(id, "a", 3));
+    }
+}
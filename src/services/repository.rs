@@ -0,0 +1,274 @@
+use std::marker::PhantomData;
+use std::sync::Arc;
+use crate::guid::Guid;
+use crate::services::aggregate::{Aggregate, AggregateErr};
+use crate::services::event_store::{EventEnvelope, EventMetadata, EventStore, EventStoreErr, EventStoreResult, RecordedEvent, Snapshot};
+
+/// Loads, decides and appends for one aggregate type on top of an `EventStore`. Each aggregate
+/// is its own stream named after its type, so any number of aggregate types share one store
+/// and one global log.
+pub struct EventSourcedRepository<A: Aggregate> {
+    event_repo: Arc<dyn EventStore>,
+    /// A snapshot is stored whenever an aggregate passes a multiple of this version. 0 disables snapshots.
+    snapshot_interval: u32,
+    aggregate: PhantomData<fn() -> A>,
+}
+
+impl<A: Aggregate> Clone for EventSourcedRepository<A> {
+    fn clone(&self) -> EventSourcedRepository<A> {
+        EventSourcedRepository::new(self.event_repo.clone(), self.snapshot_interval)
+    }
+}
+
+impl<A: Aggregate> EventSourcedRepository<A> {
+    pub fn new(event_repo: Arc<dyn EventStore>, snapshot_interval: u32) -> EventSourcedRepository<A> {
+        EventSourcedRepository {
+            event_repo,
+            snapshot_interval,
+            aggregate: PhantomData,
+        }
+    }
+
+    pub fn event_store(&self) -> Arc<dyn EventStore> {
+        self.event_repo.clone()
+    }
+
+    /// The id of the aggregate stored in `stream`.
+    pub fn id_of(stream: &str) -> EventStoreResult<Guid> {
+        stream
+            .strip_prefix(A::TYPE)
+            .and_then(|rest| rest.strip_prefix('-'))
+            .ok_or_else(|| EventStoreErr::StorageErr(format!("{} is not a {} stream", stream, A::TYPE)))
+            .and_then(|id| Guid::from_str(id).map_err(EventStoreErr::StorageErr))
+    }
+
+    /// Current state of aggregate `id`, folded from its latest snapshot and the events after it.
+    pub async fn load(&self, id: Guid) -> EventStoreResult<A> {
+        let stream = A::stream_name(id);
+        let (state, version) = match self.event_repo.get_snapshot(&stream).await? {
+            None => { (A::from_events(vec![]), 0) }
+            Some(snapshot) => {
+                match serde_json::from_value(snapshot.state) {
+                    Ok(state) => { (state, snapshot.version) }
+                    Err(err) => {
+                        // Snapshots only save replay time, so one that no longer reads is ignored.
+                        log::warn!("Ignoring unreadable snapshot of {}: {}", stream, err);
+                        (A::from_events(vec![]), 0)
+                    }
+                }
+            }
+        };
+        let events = self.event_repo
+            .get_events_after(&stream, version).await?
+            .into_iter()
+            .map(|envelope| envelope.parse().map(|envelope| envelope.event))
+            .collect::<EventStoreResult<Vec<A::Event>>>()?;
+
+        Ok(A::from_snapshot(state, events))
+    }
+
+    /// Every event of aggregate `id` in version order.
+    pub async fn history(&self, id: Guid) -> EventStoreResult<Vec<EventEnvelope<A::Event>>> {
+        self.event_repo
+            .get(&A::stream_name(id)).await?
+            .into_iter()
+            .map(EventEnvelope::parse)
+            .collect()
+    }
+
+    /// Every aggregate of this type with its events.
+    pub async fn list(&self) -> EventStoreResult<Vec<(Guid, Vec<EventEnvelope<A::Event>>)>> {
+        self.event_repo
+            .list(A::TYPE).await?
+            .into_iter()
+            .map(|stream| {
+                let events = stream.events
+                    .into_iter()
+                    .map(EventEnvelope::parse)
+                    .collect::<EventStoreResult<Vec<EventEnvelope<A::Event>>>>()?;
+                Ok((Self::id_of(&stream.name)?, events))
+            })
+            .collect()
+    }
+
+    /// Reads up to `limit` events of this aggregate type after `after_position`, in position order.
+    pub async fn read(&self, after_position: u64, limit: usize) -> EventStoreResult<Vec<RecordedEvent<A::Event>>> {
+        self.event_repo
+            .read_category(A::TYPE, after_position, limit).await?
+            .into_iter()
+            .map(RecordedEvent::parse)
+            .collect()
+    }
+
+    /// Lets `decide` turn the current state of aggregate `id` into new events and appends
+    /// them, as long as nothing else was appended since the state was loaded. Returns the new
+    /// state and the stored events.
+    pub async fn execute<Err, D>(&self, id: Guid, metadata: &EventMetadata, decide: D) -> Result<(A, Vec<RecordedEvent<A::Event>>), Err>
+        where Err: From<EventStoreErr> + From<AggregateErr>,
+              D: FnOnce(&A) -> Result<Vec<A::Event>, Err> + Send {
+        let state = self.load(id).await?;
+
+        self.decide_and_append(id, state, metadata, decide).await
+    }
+
+    /// Like `execute` for an aggregate that does not exist yet, so `decide` sees its empty state.
+    pub async fn create<Err, D>(&self, id: Guid, metadata: &EventMetadata, decide: D) -> Result<(A, Vec<RecordedEvent<A::Event>>), Err>
+        where Err: From<EventStoreErr> + From<AggregateErr>,
+              D: FnOnce(&A) -> Result<Vec<A::Event>, Err> + Send {
+        self.decide_and_append(id, A::from_events(vec![]), metadata, decide).await
+    }
+
+    async fn decide_and_append<Err, D>(&self, id: Guid, mut state: A, metadata: &EventMetadata, decide: D) -> Result<(A, Vec<RecordedEvent<A::Event>>), Err>
+        where Err: From<EventStoreErr> + From<AggregateErr>,
+              D: FnOnce(&A) -> Result<Vec<A::Event>, Err> + Send {
+        let expected_version = state.version();
+        let mut envelopes = vec![];
+        for event in decide(&state)? {
+            let valid_event = state.try_apply(event)?;
+            state = state.apply(&valid_event);
+            envelopes.push(EventEnvelope::new(valid_event.into(), metadata).to_raw()?);
+        }
+
+        let recorded = self.event_repo
+            .append(&A::stream_name(id), expected_version, envelopes).await?
+            .into_iter()
+            .map(RecordedEvent::parse)
+            .collect::<EventStoreResult<Vec<RecordedEvent<A::Event>>>>()?;
+        self.snapshot_if_due(id, &state, expected_version).await;
+
+        Ok((state, recorded))
+    }
+
+    async fn snapshot_if_due(&self, id: Guid, state: &A, previous_version: u32) {
+        if self.snapshot_interval == 0 || state.version() / self.snapshot_interval == previous_version / self.snapshot_interval {
+            return;
+        }
+
+        // The events are already stored, so a missing snapshot only costs replay time later.
+        let saved = match serde_json::to_value(state) {
+            Ok(value) => {
+                let snapshot = Snapshot {
+                    stream: A::stream_name(id),
+                    version: state.version(),
+                    state: value,
+                };
+                self.event_repo.save_snapshot(&snapshot).await
+            }
+            Err(err) => { Err(EventStoreErr::StorageErr(err.to_string())) }
+        };
+        if let Err(err) = saved {
+            log::warn!("Could not snapshot {} at version {}: {}", A::stream_name(id), state.version(), err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use serde_derive::{Deserialize, Serialize};
+    use crate::guid::Guid;
+    use crate::services::aggregate::{Aggregate, AggregateErr, DomainError, DomainEvent};
+    use crate::services::event_store::{EventMetadata, EventStore, EventStoreErr, InMemoryEventStore};
+    use crate::services::repository::EventSourcedRepository;
+
+    /// A second aggregate type, to show the repository needs nothing todo specific.
+    #[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+    struct Counter {
+        count: u32,
+    }
+
+    #[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+    struct Incremented {
+        version: u32,
+    }
+
+    impl DomainEvent for Incremented {
+        fn version(&self) -> u32 {
+            self.version
+        }
+    }
+
+    impl Aggregate for Counter {
+        const TYPE: &'static str = "counter";
+        type Event = Incremented;
+        type ValidEvent = Incremented;
+        type Command = ();
+
+        fn version(&self) -> u32 {
+            self.count
+        }
+
+        fn decide(&self, _: ()) -> Result<Vec<Incremented>, DomainError> {
+            Ok(vec![Incremented { version: self.count + 1 }])
+        }
+
+        fn try_apply(&self, event: Incremented) -> Result<Incremented, AggregateErr> {
+            if event.version == self.count + 1 { Ok(event) } else { Err(AggregateErr::ConcurrencyErr) }
+        }
+
+        fn apply(self, _: &Incremented) -> Counter {
+            Counter { count: self.count + 1 }
+        }
+
+        fn from_events(events: Vec<Incremented>) -> Counter {
+            Counter::from_snapshot(Counter { count: 0 }, events)
+        }
+
+        fn from_snapshot(state: Counter, events: Vec<Incremented>) -> Counter {
+            Counter { count: state.count + events.len() as u32 }
+        }
+    }
+
+    /// What the counter tests get back from the repository.
+    #[derive(Debug)]
+    enum CounterErr {
+        Store(EventStoreErr),
+        Rejected,
+    }
+
+    impl From<EventStoreErr> for CounterErr {
+        fn from(err: EventStoreErr) -> CounterErr {
+            CounterErr::Store(err)
+        }
+    }
+
+    impl From<AggregateErr> for CounterErr {
+        fn from(_: AggregateErr) -> CounterErr {
+            CounterErr::Rejected
+        }
+    }
+
+    fn increment(counter: &Counter) -> Result<Vec<Incremented>, CounterErr> {
+        counter.decide(()).map_err(|_| CounterErr::Rejected)
+    }
+
+    #[rocket::async_test]
+    async fn streams_are_named_after_the_aggregate_type() {
+        let event_repo: Arc<dyn EventStore> = Arc::new(InMemoryEventStore::default());
+        let counters = EventSourcedRepository::<Counter>::new(event_repo.clone(), 2);
+        let metadata = EventMetadata::default();
+        let id = Guid::new();
+
+        counters.create(id, &metadata, increment).await.unwrap();
+        for _ in 0..4 {
+            counters.execute(id, &metadata, increment).await.unwrap();
+        }
+
+        assert_eq!(counters.load(id).await.unwrap(), Counter { count: 5 });
+        assert_eq!(event_repo.get(&format!("counter-{}", id)).await.unwrap().len(), 5);
+        assert_eq!(event_repo.get_snapshot(&format!("counter-{}", id)).await.unwrap().unwrap().version, 4);
+        assert_eq!(counters.list().await.unwrap()[0].0, id);
+        assert!(event_repo.read_category("todo", 0, 10).await.unwrap().is_empty());
+    }
+
+    #[rocket::async_test]
+    async fn execute_fails_for_unknown_aggregates_and_create_for_existing_ones() {
+        let counters = EventSourcedRepository::<Counter>::new(Arc::new(InMemoryEventStore::default()), 0);
+        let metadata = EventMetadata::default();
+        let id = Guid::new();
+
+        assert!(matches!(counters.execute(id, &metadata, increment).await, Err(CounterErr::Store(EventStoreErr::NotFound(_)))));
+        counters.create(id, &metadata, increment).await.unwrap();
+        assert!(matches!(counters.create(id, &metadata, increment).await, Err(CounterErr::Store(EventStoreErr::ConcurrencyErr { .. }))));
+    }
+}
@@ -0,0 +1,92 @@
+use std::fmt::Debug;
+use crate::services::aggregate::{Aggregate, AggregateErr, DomainError};
+
+/// Given/When/Then tests for any `Aggregate`: start from prior events, run a command or an
+/// event through the aggregate the way the repository would, and assert what came out.
+///
+/// ```ignore
+/// Scenario::<TodoAggregate>::given(vec![created])
+///     .when(TodoCommand::CompleteTodo { cascade: false })
+///     .then_events(vec![TodoEvent::ChangeStatus { status: Status::Complete, version: 2 }]);
+/// ```
+pub struct Scenario<A: Aggregate> {
+    state: A,
+}
+
+impl<A: Aggregate> Scenario<A> {
+    pub fn given(events: Vec<A::Event>) -> Scenario<A> {
+        Scenario {
+            state: A::from_events(events),
+        }
+    }
+
+    pub fn given_nothing() -> Scenario<A> {
+        Scenario::given(vec![])
+    }
+
+    /// Decides `command` and applies the resulting events.
+    pub fn when(self, command: A::Command) -> Outcome<A> {
+        let result = self.state
+            .decide(command)
+            .map_err(Failure::Rejected)
+            .and_then(|events| Scenario::apply(self.state.clone(), events));
+
+        Outcome { result }
+    }
+
+    /// Applies `event` directly, bypassing `decide`.
+    pub fn when_event(self, event: A::Event) -> Outcome<A> {
+        Outcome {
+            result: Scenario::apply(self.state, vec![event]),
+        }
+    }
+
+    fn apply(mut state: A, events: Vec<A::Event>) -> Result<(A, Vec<A::Event>), Failure> {
+        for event in events.iter().cloned() {
+            let valid_event = state.try_apply(event).map_err(Failure::Invalid)?;
+            state = state.apply(&valid_event);
+        }
+
+        Ok((state, events))
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Failure {
+    Rejected(DomainError),
+    Invalid(AggregateErr),
+}
+
+pub struct Outcome<A: Aggregate> {
+    result: Result<(A, Vec<A::Event>), Failure>,
+}
+
+impl<A> Outcome<A> where A: Aggregate + Debug, A::Event: Debug + PartialEq {
+    /// Asserts the events that were produced and returns the state after applying them.
+    pub fn then_events(self, expected: Vec<A::Event>) -> A {
+        match self.result {
+            Ok((state, events)) => {
+                assert_eq!(events, expected);
+                state
+            }
+            Err(failure) => { panic!("expected events {:?}, got {:?}", expected, failure) }
+        }
+    }
+
+    /// Asserts that `decide` rejected the command.
+    pub fn then_rejected(self, expected: DomainError) {
+        self.then_failure(Failure::Rejected(expected));
+    }
+
+    /// Asserts that an event was refused by `try_apply`.
+    pub fn then_error(self, expected: AggregateErr) {
+        self.then_failure(Failure::Invalid(expected));
+    }
+
+    fn then_failure(self, expected: Failure) {
+        match self.result {
+            Ok((_, events)) => { panic!("expected {:?}, got events {:?}", expected, events) }
+            Err(failure) => { assert_eq!(failure, expected) }
+        }
+    }
+}
@@ -0,0 +1,593 @@
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+use rusqlite::{Connection, ErrorCode, OptionalExtension, params, Row, TransactionBehavior};
+use rusqlite::types::ValueRef;
+use serde_json::Value;
+use crate::guid::Guid;
+use crate::routes::todo::Todo;
+use crate::services::aggregate::{Aggregate, TodoAggregate};
+use crate::services::codec::{self, EventCodec};
+use crate::services::data::{DataAccessErr, DataAccessResult, TodoRepository};
+use crate::services::event_store::{EventEnvelope, EventStore, EventStoreErr, EventStoreResult, RecordedEvent, Snapshot, StoredStream};
+
+// SQLite calls are short and local, so they run inline on the async worker instead of being
+// moved to a blocking thread pool.
+
+const EVENT_SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS todo_events (
+        position INTEGER PRIMARY KEY AUTOINCREMENT,
+        stream_id TEXT NOT NULL,
+        version INTEGER NOT NULL,
+        content_type TEXT NOT NULL DEFAULT 'application/json',
+        payload BLOB NOT NULL,
+        UNIQUE (stream_id, version)
+    );
+    CREATE TABLE IF NOT EXISTS todo_snapshots (
+        stream_id TEXT PRIMARY KEY,
+        version INTEGER NOT NULL,
+        payload TEXT NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS subscription_checkpoints (
+        name TEXT PRIMARY KEY,
+        position INTEGER NOT NULL
+    );
+";
+
+const TODO_SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS todos (
+        id TEXT PRIMARY KEY,
+        payload TEXT NOT NULL
+    );
+";
+
+fn open(path: &str, schema: &str) -> Result<Connection, String> {
+    let conn = Connection::open(path)
+        .map_err(|err| format!("Could not open SQLite database {}: {}", path, err))?;
+    // The event store and the read model each hold a connection to the same file.
+    conn.busy_timeout(Duration::from_secs(5))
+        .map_err(|err| format!("Could not configure SQLite database {}: {}", path, err))?;
+    conn.execute_batch(schema)
+        .map_err(|err| format!("Could not create SQLite schema in {}: {}", path, err))?;
+
+    Ok(conn)
+}
+
+fn is_constraint_violation(err: &rusqlite::Error) -> bool {
+    matches!(err.sqlite_error_code(), Some(ErrorCode::ConstraintViolation))
+}
+
+/// Stores one row per event. The unique (stream_id, version) index makes the database itself
+/// reject a second writer appending the same version, and the autoincrement `position` is the
+/// event's place in the global log.
+pub struct SqliteEventStore {
+    codec: Arc<dyn EventCodec>,
+    conn: Mutex<Connection>,
+}
+
+impl SqliteEventStore {
+    /// Opens the store, writing new events with `codec`.
+    pub fn open(path: &str, codec: Arc<dyn EventCodec>) -> Result<SqliteEventStore, String> {
+        let conn = open(path, EVENT_SCHEMA)?;
+        // Tables created before codecs only ever held JSON.
+        let has_content_type = conn
+            .prepare("SELECT 1 FROM pragma_table_info('todo_events') WHERE name = 'content_type'")
+            .and_then(|mut statement| statement.exists([]))
+            .map_err(|err| format!("Could not inspect SQLite schema in {}: {}", path, err))?;
+        if !has_content_type {
+            conn.execute_batch("ALTER TABLE todo_events ADD COLUMN content_type TEXT NOT NULL DEFAULT 'application/json'")
+                .map_err(|err| format!("Could not add content_type to todo_events in {}: {}", path, err))?;
+        }
+        // Streams stored before stream names carried the aggregate type all belong to todos.
+        // Their snapshots are dropped and taken again as the todos change.
+        conn.execute(
+            "UPDATE todo_events SET stream_id = ?1 || stream_id WHERE length(stream_id) = 36",
+            params![format!("{}-", TodoAggregate::TYPE)],
+        ).and_then(|_| conn.execute("DELETE FROM todo_snapshots WHERE length(stream_id) = 36", []))
+            .map_err(|err| format!("Could not rename streams in {}: {}", path, err))?;
+
+        Ok(SqliteEventStore {
+            codec,
+            conn: Mutex::new(conn),
+        })
+    }
+
+    fn conn(&self) -> EventStoreResult<MutexGuard<'_, Connection>> {
+        self.conn
+            .lock()
+            .map_err(|_| EventStoreErr::StorageErr("SQLite connection is unavailable".to_string()))
+    }
+
+    fn read_events(&self, stream: &str, after_version: u32) -> EventStoreResult<Vec<EventEnvelope<Value>>> {
+        let conn = self.conn()?;
+        let mut statement = conn
+            .prepare("SELECT content_type, payload FROM todo_events WHERE stream_id = ?1 AND version > ?2 ORDER BY version")
+            .map_err(|_| EventStoreErr::StorageErr("Could not find events".to_string()))?;
+        let rows = statement
+            .query_map(params![stream, after_version], |row| Ok((row.get(0)?, payload(row, 1)?)))
+            .map_err(|_| EventStoreErr::StorageErr("Could not find events".to_string()))?;
+
+        let mut events = vec![];
+        for row in rows {
+            let (content_type, payload): (String, Vec<u8>) = row.map_err(|_| EventStoreErr::StorageErr("Could not find events".to_string()))?;
+            events.push(decode_event(&content_type, &payload)?);
+        }
+
+        Ok(events)
+    }
+
+    /// Reads the log in position order, only from the streams of `category` if one is given.
+    fn read_log(&self, category: Option<&str>, after_position: u64, limit: usize) -> EventStoreResult<Vec<RecordedEvent<Value>>> {
+        let conn = self.conn()?;
+        let mut statement = conn
+            .prepare("
+                SELECT position, stream_id, version, content_type, payload FROM todo_events
+                WHERE position > ?1 AND (?3 IS NULL OR substr(stream_id, 1, length(?3)) = ?3)
+                ORDER BY position LIMIT ?2
+            ")
+            .map_err(|_| EventStoreErr::StorageErr("Could not read events".to_string()))?;
+        let prefix = category.map(|category| format!("{}-", category));
+        let rows = statement
+            .query_map(params![after_position as i64, limit as i64, prefix], |row| {
+                Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?, row.get::<_, u32>(2)?, row.get::<_, String>(3)?, payload(row, 4)?))
+            })
+            .map_err(|_| EventStoreErr::StorageErr("Could not read events".to_string()))?;
+
+        let mut results = vec![];
+        for row in rows {
+            let (position, stream, version, content_type, payload) = row.map_err(|_| EventStoreErr::StorageErr("Could not read events".to_string()))?;
+            results.push(RecordedEvent {
+                position: position as u64,
+                stream,
+                version,
+                event: decode_event(&content_type, &payload)?,
+            });
+        }
+
+        Ok(results)
+    }
+
+    fn stream_version(conn: &Connection, stream: &str) -> EventStoreResult<u32> {
+        conn
+            .query_row(
+                "SELECT COALESCE(MAX(version), 0) FROM todo_events WHERE stream_id = ?1",
+                params![stream],
+                |row| row.get(0),
+            )
+            .map_err(|_| EventStoreErr::StorageErr("Could not read stream version".to_string()))
+    }
+}
+
+/// Events written before codecs are stored as JSON text rather than a blob.
+fn payload(row: &Row, index: usize) -> rusqlite::Result<Vec<u8>> {
+    match row.get_ref(index)? {
+        ValueRef::Text(bytes) | ValueRef::Blob(bytes) => { Ok(bytes.to_vec()) }
+        other => { Err(rusqlite::Error::InvalidColumnType(index, "payload".to_string(), other.data_type())) }
+    }
+}
+
+fn decode_event(content_type: &str, payload: &[u8]) -> EventStoreResult<EventEnvelope<Value>> {
+    codec::decode(content_type, payload).map_err(EventStoreErr::StorageErr)
+}
+
+#[async_trait]
+impl EventStore for SqliteEventStore {
+    async fn get(&self, stream: &str) -> EventStoreResult<Vec<EventEnvelope<Value>>> {
+        let events = self.read_events(stream, 0)?;
+        if events.is_empty() {
+            return Err(EventStoreErr::NotFound(stream.to_string()));
+        }
+
+        Ok(events)
+    }
+
+    async fn list(&self, category: &str) -> EventStoreResult<Vec<StoredStream>> {
+        let conn = self.conn()?;
+        let mut statement = conn
+            .prepare("
+                SELECT stream_id, content_type, payload FROM todo_events
+                WHERE substr(stream_id, 1, length(?1)) = ?1
+                ORDER BY stream_id, version
+            ")
+            .map_err(|_| EventStoreErr::StorageErr("Could not list".to_string()))?;
+        let rows = statement
+            .query_map(params![format!("{}-", category)], |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?, payload(row, 2)?)))
+            .map_err(|_| EventStoreErr::StorageErr("Could not list".to_string()))?;
+
+        let mut results: Vec<StoredStream> = vec![];
+        for row in rows {
+            let (name, content_type, payload) = row.map_err(|_| EventStoreErr::StorageErr("Could not list".to_string()))?;
+            let event = decode_event(&content_type, &payload)?;
+            match results.last_mut() {
+                Some(stream) if stream.name == name => { stream.events.push(event) }
+                _ => { results.push(StoredStream { name, events: vec![event] }) }
+            }
+        }
+
+        Ok(results)
+    }
+
+    async fn append(&self, stream: &str, expected_version: u32, events: Vec<EventEnvelope<Value>>) -> EventStoreResult<Vec<RecordedEvent<Value>>> {
+        let mut conn = self.conn()?;
+        let tx = conn
+            .transaction()
+            .map_err(|_| EventStoreErr::StorageErr("Could not start transaction".to_string()))?;
+        let conflict = |err: rusqlite::Error| {
+            if is_constraint_violation(&err) {
+                EventStoreErr::ConcurrencyErr { stream: stream.to_string(), expected_version }
+            } else {
+                EventStoreErr::StorageErr("Could not insert events".to_string())
+            }
+        };
+
+        let version = SqliteEventStore::stream_version(&tx, stream)?;
+        if version == 0 && expected_version != 0 {
+            return Err(EventStoreErr::NotFound(stream.to_string()));
+        }
+        if version != expected_version {
+            return Err(EventStoreErr::ConcurrencyErr { stream: stream.to_string(), expected_version });
+        }
+
+        let mut recorded = vec![];
+        for (i, event) in events.into_iter().enumerate() {
+            let version = expected_version + 1 + i as u32;
+            let payload = self.codec
+                .encode(&event)
+                .map_err(EventStoreErr::StorageErr)?;
+            tx.execute(
+                "INSERT INTO todo_events (stream_id, version, content_type, payload) VALUES (?1, ?2, ?3, ?4)",
+                params![stream, version, self.codec.content_type(), payload],
+            ).map_err(conflict)?;
+            recorded.push(RecordedEvent {
+                position: tx.last_insert_rowid() as u64,
+                stream: stream.to_string(),
+                version,
+                event,
+            });
+        }
+
+        tx.commit().map_err(conflict)?;
+
+        Ok(recorded)
+    }
+
+    async fn read_all(&self, after_position: u64, limit: usize) -> EventStoreResult<Vec<RecordedEvent<Value>>> {
+        self.read_log(None, after_position, limit)
+    }
+
+    async fn read_category(&self, category: &str, after_position: u64, limit: usize) -> EventStoreResult<Vec<RecordedEvent<Value>>> {
+        self.read_log(Some(category), after_position, limit)
+    }
+
+    async fn last_position(&self) -> EventStoreResult<u64> {
+        self.conn()?
+            .query_row("SELECT COALESCE(MAX(position), 0) FROM todo_events", [], |row| row.get::<_, i64>(0))
+            .map(|position| position as u64)
+            .map_err(|_| EventStoreErr::StorageErr("Could not read the last position".to_string()))
+    }
+
+    async fn get_events_after(&self, stream: &str, version: u32) -> EventStoreResult<Vec<EventEnvelope<Value>>> {
+        let events = self.read_events(stream, version)?;
+        if events.is_empty() && SqliteEventStore::stream_version(&*self.conn()?, stream)? == 0 {
+            return Err(EventStoreErr::NotFound(stream.to_string()));
+        }
+
+        Ok(events)
+    }
+
+    async fn get_snapshot(&self, stream: &str) -> EventStoreResult<Option<Snapshot>> {
+        let payload: Option<String> = self.conn()?
+            .query_row(
+                "SELECT payload FROM todo_snapshots WHERE stream_id = ?1",
+                params![stream],
+                |row| row.get(0),
+            )
+            .optional()
+            .map_err(|_| EventStoreErr::StorageErr("Could not find snapshot".to_string()))?;
+
+        match payload {
+            None => { Ok(None) }
+            Some(payload) => {
+                serde_json::from_str(&payload)
+                    .map(Some)
+                    .map_err(|_| EventStoreErr::StorageErr("Could not deserialize snapshot".to_string()))
+            }
+        }
+    }
+
+    async fn save_snapshot(&self, snapshot: &Snapshot) -> EventStoreResult<()> {
+        let payload = serde_json::to_string(snapshot)
+            .map_err(|_| EventStoreErr::StorageErr("Could not serialize snapshot".to_string()))?;
+
+        self.conn()?
+            .execute(
+                "INSERT OR REPLACE INTO todo_snapshots (stream_id, version, payload) VALUES (?1, ?2, ?3)",
+                params![snapshot.stream, snapshot.version, payload],
+            )
+            .map(|_| ())
+            .map_err(|_| EventStoreErr::StorageErr("Could not save snapshot".to_string()))
+    }
+
+    async fn get_checkpoint(&self, name: &str) -> EventStoreResult<Option<u64>> {
+        self.conn()?
+            .query_row(
+                "SELECT position FROM subscription_checkpoints WHERE name = ?1",
+                params![name],
+                |row| row.get::<_, i64>(0),
+            )
+            .optional()
+            .map(|position| position.map(|position| position as u64))
+            .map_err(|_| EventStoreErr::StorageErr("Could not find checkpoint".to_string()))
+    }
+
+    async fn save_checkpoint(&self, name: &str, position: u64) -> EventStoreResult<()> {
+        self.conn()?
+            .execute(
+                "INSERT OR REPLACE INTO subscription_checkpoints (name, position) VALUES (?1, ?2)",
+                params![name, position as i64],
+            )
+            .map(|_| ())
+            .map_err(|_| EventStoreErr::StorageErr("Could not save checkpoint".to_string()))
+    }
+    async fn delete_stream(&self, stream: &str, expected_version: u32) -> EventStoreResult<()> {
+        let mut conn = self.conn()?;
+        // Takes the write lock up front, so no append lands between the check and the delete.
+        let tx = conn
+            .transaction_with_behavior(TransactionBehavior::Immediate)
+            .map_err(|_| EventStoreErr::StorageErr("Could not start transaction".to_string()))?;
+        if SqliteEventStore::stream_version(&tx, stream)? != expected_version {
+            return Err(EventStoreErr::ConcurrencyErr { stream: stream.to_string(), expected_version });
+        }
+        tx.execute("DELETE FROM todo_events WHERE stream_id = ?1", params![stream])
+            .and_then(|_| tx.execute("DELETE FROM todo_snapshots WHERE stream_id = ?1", params![stream]))
+            .map_err(|_| EventStoreErr::StorageErr("Could not delete stream".to_string()))?;
+
+        tx.commit().map_err(|_| EventStoreErr::StorageErr("Could not delete stream".to_string()))
+    }
+}
+
+pub struct SqliteTodoRepository {
+    conn: Mutex<Connection>,
+}
+
+impl SqliteTodoRepository {
+    pub fn open(path: &str) -> Result<SqliteTodoRepository, String> {
+        Ok(SqliteTodoRepository {
+            conn: Mutex::new(open(path, TODO_SCHEMA)?)
+        })
+    }
+
+    fn conn(&self) -> DataAccessResult<MutexGuard<'_, Connection>> {
+        self.conn
+            .lock()
+            .map_err(|_| DataAccessErr::new("SQLite connection is unavailable"))
+    }
+
+    fn all(&self) -> DataAccessResult<Vec<Todo>> {
+        let conn = self.conn()?;
+        let mut statement = conn
+            .prepare("SELECT payload FROM todos")
+            .map_err(|_| DataAccessErr::new("Could not list todos"))?;
+        let rows = statement
+            .query_map([], |row| row.get::<_, String>(0))
+            .map_err(|_| DataAccessErr::new("Could not list todos"))?;
+
+        let mut results = vec![];
+        for row in rows {
+            let payload = row.map_err(|_| DataAccessErr::new("Could not list todos"))?;
+            results.push(deserialize_todo(&payload)?);
+        }
+
+        Ok(results)
+    }
+}
+
+fn serialize_todo(todo: &Todo) -> DataAccessResult<String> {
+    serde_json::to_string(todo).map_err(|_| DataAccessErr::new("Could not serialize todo"))
+}
+
+fn deserialize_todo(payload: &str) -> DataAccessResult<Todo> {
+    serde_json::from_str(payload).map_err(|_| DataAccessErr::new("Could not deserialize todo"))
+}
+
+#[async_trait]
+impl TodoRepository for SqliteTodoRepository {
+    async fn get_by_id(&self, id: Guid) -> DataAccessResult<Todo> {
+        let payload: Option<String> = self.conn()?
+            .query_row("SELECT payload FROM todos WHERE id = ?1", params![id.to_string()], |row| row.get(0))
+            .optional()
+            .map_err(|_| DataAccessErr::new("Could not find todo"))?;
+
+        match payload {
+            None => { Err(DataAccessErr::not_found("Could not find todo")) }
+            Some(payload) => { deserialize_todo(&payload) }
+        }
+    }
+
+    async fn list(&self) -> DataAccessResult<Vec<Todo>> {
+        Ok(self.all()?.into_iter().filter(|todo| todo.deleted_at.is_none()).collect())
+    }
+
+    async fn list_deleted(&self) -> DataAccessResult<Vec<Todo>> {
+        Ok(self.all()?.into_iter().filter(|todo| todo.deleted_at.is_some()).collect())
+    }
+
+    async fn upsert(&self, todo: Todo) -> DataAccessResult<Todo> {
+        self.conn()?
+            .execute(
+                "INSERT OR REPLACE INTO todos (id, payload) VALUES (?1, ?2)",
+                params![todo.id.to_string(), serialize_todo(&todo)?],
+            )
+            .map_err(|_| DataAccessErr::new("Could not upsert todo"))?;
+
+        Ok(todo)
+    }
+
+    async fn upsert_if_newer(&self, todo: Todo) -> DataAccessResult<bool> {
+        let stored = self.conn()?
+            .execute(
+                "INSERT INTO todos (id, payload) VALUES (?1, ?2)
+                 ON CONFLICT (id) DO UPDATE SET payload = excluded.payload
+                 WHERE json_extract(todos.payload, '$.version') < json_extract(excluded.payload, '$.version')",
+                params![todo.id.to_string(), serialize_todo(&todo)?],
+            )
+            .map_err(|_| DataAccessErr::new("Could not upsert todo"))?;
+
+        Ok(stored > 0)
+    }
+
+    async fn delete(&self, id: Guid) -> DataAccessResult<()> {
+        self.conn()?
+            .execute("DELETE FROM todos WHERE id = ?1", params![id.to_string()])
+            .map(|_| ())
+            .map_err(|_| DataAccessErr::new("Could not delete todo"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use rusqlite::{params, Connection};
+    use serde_json::Value;
+    use crate::guid::Guid;
+    use crate::routes::todo::{Status, Todo};
+    use crate::services::codec::{CborCodec, JsonCodec, MessagePackCodec};
+    use crate::services::aggregate::{Aggregate, TodoAggregate, TodoEvent};
+    use crate::services::event_store::{EventEnvelope, EventMetadata, EventStore, EventStoreErr, Snapshot};
+    use crate::services::data::TodoRepository;
+    use crate::services::sqlite::{SqliteEventStore, SqliteTodoRepository, EVENT_SCHEMA};
+
+    fn envelope(event: TodoEvent) -> EventEnvelope<Value> {
+        EventEnvelope::new(event, &EventMetadata::default()).to_raw().unwrap()
+    }
+
+    fn events(envelopes: Vec<EventEnvelope<Value>>) -> Vec<TodoEvent> {
+        envelopes.into_iter().map(|envelope| envelope.parse().unwrap().event).collect()
+    }
+
+    fn temp_db(name: &str) -> String {
+        std::env::temp_dir().join(format!("todo-{}-{}.db", name, Guid::new())).to_str().unwrap().to_string()
+    }
+
+    #[rocket::async_test]
+    async fn older_todos_do_not_replace_newer_ones() {
+        let repo = SqliteTodoRepository::open(":memory:").unwrap();
+        let id = Guid::new();
+        let todo = |name: &str, version: u32| Todo {
+            id,
+            status: Status::Incomplete,
+            name: name.to_string(),
+            due: None,
+            overdue: false,
+            list: None,
+            parent: None,
+            version,
+            deleted_at: None,
+        };
+
+        assert!(repo.upsert_if_newer(todo("a", 1)).await.unwrap());
+        assert!(repo.upsert_if_newer(todo("b", 3)).await.unwrap());
+        assert!(!repo.upsert_if_newer(todo("c", 2)).await.unwrap());
+        assert!(!repo.upsert_if_newer(todo("d", 3)).await.unwrap());
+        assert_eq!(repo.get_by_id(id).await.unwrap(), todo("b", 3));
+    }
+
+    #[rocket::async_test]
+    async fn append_rejects_a_stale_expected_version() {
+        let store = SqliteEventStore::open(":memory:", Arc::new(JsonCodec)).unwrap();
+        let id = Guid::new();
+        let stream = TodoAggregate::stream_name(id);
+        store.append(&stream, 0, vec![envelope(TodoEvent::Create { name: "a".to_string(), id })]).await.unwrap();
+        store.append(&stream, 1, vec![envelope(TodoEvent::ChangeName { new_name: "b".to_string(), version: 2 })]).await.unwrap();
+
+        let stale = store.append(&stream, 1, vec![envelope(TodoEvent::ChangeName { new_name: "c".to_string(), version: 2 })]).await;
+        assert!(matches!(stale, Err(EventStoreErr::ConcurrencyErr { .. })));
+
+        assert_eq!(store.get(&stream).await.unwrap().len(), 2);
+        assert_eq!(store.get_events_after(&stream, 1).await.unwrap().len(), 1);
+    }
+
+    #[rocket::async_test]
+    async fn read_all_returns_events_of_every_stream_in_append_order() {
+        let store = SqliteEventStore::open(":memory:", Arc::new(JsonCodec)).unwrap();
+        let first = Guid::new();
+        let second = Guid::new();
+        let (first_stream, second_stream) = (TodoAggregate::stream_name(first), format!("other-{}", second));
+        store.append(&first_stream, 0, vec![envelope(TodoEvent::Create { name: "a".to_string(), id: first })]).await.unwrap();
+        store.append(&second_stream, 0, vec![envelope(TodoEvent::Create { name: "b".to_string(), id: second })]).await.unwrap();
+        let appended = store.append(&first_stream, 1, vec![envelope(TodoEvent::Delete { version: 2 })]).await.unwrap();
+        assert_eq!((appended[0].position, appended[0].version), (3, 2));
+
+        let streams: Vec<String> = store.read_all(0, 10).await.unwrap().into_iter().map(|event| event.stream).collect();
+        assert_eq!(streams, vec![first_stream.clone(), second_stream.clone(), first_stream.clone()]);
+        let positions: Vec<u64> = store.read_category("todo", 0, 10).await.unwrap().iter().map(|event| event.position).collect();
+        assert_eq!(positions, vec![1, 3]);
+        assert_eq!(store.list("other").await.unwrap()[0].name, second_stream);
+
+        let batch = store.read_all(1, 1).await.unwrap();
+        assert_eq!(batch.len(), 1);
+        assert_eq!(batch[0].position, 2);
+        assert_eq!(store.last_position().await.unwrap(), 3);
+    }
+
+    #[rocket::async_test]
+    async fn reads_events_written_with_different_codecs() {
+        let path = temp_db("codecs");
+        let id = Guid::new();
+        let stream = TodoAggregate::stream_name(id);
+
+        let json = SqliteEventStore::open(&path, Arc::new(JsonCodec)).unwrap();
+        json.append(&stream, 0, vec![envelope(TodoEvent::Create { name: "a".to_string(), id })]).await.unwrap();
+        drop(json);
+        let cbor = SqliteEventStore::open(&path, Arc::new(CborCodec)).unwrap();
+        cbor.append(&stream, 1, vec![envelope(TodoEvent::ChangeName { new_name: "b".to_string(), version: 2 })]).await.unwrap();
+        drop(cbor);
+
+        let msgpack = SqliteEventStore::open(&path, Arc::new(MessagePackCodec)).unwrap();
+        let stored = msgpack.get(&stream).await.unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(events(stored), vec![
+            TodoEvent::Create { name: "a".to_string(), id },
+            TodoEvent::ChangeName { new_name: "b".to_string(), version: 2 },
+        ]);
+    }
+
+    #[rocket::async_test]
+    async fn prefixes_streams_stored_under_a_bare_id() {
+        let path = temp_db("streams");
+        let id = Guid::new();
+        let create = serde_json::to_vec(&envelope(TodoEvent::Create { name: "a".to_string(), id })).unwrap();
+        let conn = Connection::open(&path).unwrap();
+        conn.execute_batch(EVENT_SCHEMA).unwrap();
+        conn.execute(
+            "INSERT INTO todo_events (stream_id, version, payload) VALUES (?1, 1, ?2)",
+            params![id.to_string(), create],
+        ).unwrap();
+        drop(conn);
+
+        let store = SqliteEventStore::open(&path, Arc::new(JsonCodec)).unwrap();
+        let stored = store.get(&TodoAggregate::stream_name(id)).await;
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(events(stored.unwrap()), vec![TodoEvent::Create { name: "a".to_string(), id }]);
+    }
+
+    #[rocket::async_test]
+    async fn deleted_streams_leave_a_gap_in_the_log() {
+        let store = SqliteEventStore::open(":memory:", Arc::new(JsonCodec)).unwrap();
+        let (first, second) = (Guid::new(), Guid::new());
+        let (first_stream, second_stream) = (TodoAggregate::stream_name(first), TodoAggregate::stream_name(second));
+        store.append(&first_stream, 0, vec![envelope(TodoEvent::Create { name: "a".to_string(), id: first })]).await.unwrap();
+        store.append(&second_stream, 0, vec![envelope(TodoEvent::Create { name: "b".to_string(), id: second })]).await.unwrap();
+        store.save_snapshot(&Snapshot { stream: first_stream.clone(), version: 1, state: Value::Null }).await.unwrap();
+
+        let stale = store.delete_stream(&first_stream, 0).await;
+        assert!(matches!(stale, Err(EventStoreErr::ConcurrencyErr { expected_version: 0, .. })));
+        store.delete_stream(&first_stream, 1).await.unwrap();
+        assert!(matches!(store.get(&first_stream).await, Err(EventStoreErr::NotFound(_))));
+        assert!(store.get_snapshot(&first_stream).await.unwrap().is_none());
+
+        let appended = store.append(&second_stream, 1, vec![envelope(TodoEvent::Delete { version: 2 })]).await.unwrap();
+        assert_eq!(appended[0].position, 3);
+        let positions: Vec<u64> = store.read_all(0, 10).await.unwrap().iter().map(|event| event.position).collect();
+        assert_eq!(positions, vec![2, 3]);
+    }
+}
@@ -0,0 +1,264 @@
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+use chrono::{DateTime, Utc};
+use rocket::Shutdown;
+use rocket::tokio::{self, select};
+use rocket::tokio::sync::broadcast::Receiver;
+use rocket::tokio::sync::broadcast::error::RecvError;
+use serde_derive::Serialize;
+use serde_json::Value;
+use crate::services::event_store::{EventStore, EventStoreResult, RecordedEvent};
+use crate::services::feed::TodoChange;
+
+/// Receives every stored event in position order.
+#[async_trait]
+pub trait EventHandler: Send + Sync {
+    /// An error stops the subscriber at this event; it is retried until it succeeds. The
+    /// checkpoint is saved after every handled event, so an event is only delivered again
+    /// when the process stops between handling it and saving its position. Handlers that
+    /// must not act twice can compare `event.position` with what they already did.
+    async fn handle(&self, event: &RecordedEvent<Value>) -> Result<(), String>;
+}
+
+/// A named consumer of the global event log. The name identifies its checkpoint, so it must
+/// stay the same across restarts.
+pub struct Subscriber {
+    name: String,
+    handler: Box<dyn EventHandler>,
+}
+
+impl Subscriber {
+    pub fn new(name: &str, handler: Box<dyn EventHandler>) -> Subscriber {
+        Subscriber {
+            name: name.to_string(),
+            handler,
+        }
+    }
+}
+
+/// Logs a line for every event, which doubles as an audit trail.
+pub struct ActivityLog;
+
+#[async_trait]
+impl EventHandler for ActivityLog {
+    async fn handle(&self, event: &RecordedEvent<Value>) -> Result<(), String> {
+        log::info!(
+            "#{} {} version {} by {}",
+            event.position,
+            event.stream,
+            event.version,
+            event.event.actor.as_deref().unwrap_or("unknown"),
+        );
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Serialize, Clone)]
+pub struct SubscriptionStatus {
+    pub name: String,
+    pub position: u64,
+    /// How many stored events the subscriber has not processed yet.
+    pub lag: u64,
+    pub running: bool,
+    pub last_handled_at: Option<DateTime<Utc>>,
+    pub last_error: Option<String>,
+}
+
+struct SubscriptionState {
+    position: u64,
+    running: bool,
+    last_handled_at: Option<DateTime<Utc>>,
+    last_error: Option<String>,
+}
+
+struct RunningSubscriber {
+    subscriber: Subscriber,
+    state: Mutex<SubscriptionState>,
+}
+
+impl RunningSubscriber {
+    fn state(&self) -> MutexGuard<'_, SubscriptionState> {
+        match self.state.lock() {
+            Ok(state) => { state }
+            Err(poisoned) => { poisoned.into_inner() }
+        }
+    }
+
+    fn failed(&self, err: String) {
+        log::warn!("Subscriber {} failed: {}", self.subscriber.name, err);
+        self.state().last_error = Some(err);
+    }
+
+    /// Continues from the saved checkpoint and handles events until the log has nothing newer.
+    /// Returns how many events were handled.
+    async fn catch_up(&self, event_repo: &dyn EventStore, batch_size: usize) -> Result<usize, String> {
+        let mut position = event_repo
+            .get_checkpoint(&self.subscriber.name).await
+            .map_err(|err| err.to_string())?
+            .unwrap_or(0);
+        self.state().position = position;
+        let mut handled = 0;
+
+        loop {
+            let events = event_repo
+                .read_all(position, batch_size).await
+                .map_err(|err| err.to_string())?;
+            if events.is_empty() {
+                return Ok(handled);
+            }
+
+            for event in events {
+                self.subscriber.handler.handle(&event).await?;
+                event_repo
+                    .save_checkpoint(&self.subscriber.name, event.position).await
+                    .map_err(|err| err.to_string())?;
+
+                position = event.position;
+                handled += 1;
+                let mut state = self.state();
+                state.position = position;
+                state.last_handled_at = Some(Utc::now());
+                state.last_error = None;
+            }
+        }
+    }
+
+    async fn run(self: Arc<Self>, event_repo: Arc<dyn EventStore>, mut wakeups: Receiver<TodoChange>, mut shutdown: Shutdown, settings: SubscriptionSettings) {
+        self.state().running = true;
+        loop {
+            if let Err(err) = self.catch_up(event_repo.as_ref(), settings.batch_size).await {
+                self.failed(err);
+            }
+
+            // New changes wake the subscriber right away; the poll interval covers retries
+            // after errors and events appended by other processes.
+            select! {
+                received = wakeups.recv() => {
+                    if let Err(RecvError::Closed) = received {
+                        break;
+                    }
+                }
+                _ = tokio::time::sleep(settings.poll_interval) => {}
+                _ = &mut shutdown => { break; }
+            }
+        }
+        self.state().running = false;
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct SubscriptionSettings {
+    pub batch_size: usize,
+    pub poll_interval: Duration,
+}
+
+/// The registered subscribers, each of which runs as its own background task once started.
+pub struct Subscriptions {
+    event_repo: Arc<dyn EventStore>,
+    subscribers: Vec<Arc<RunningSubscriber>>,
+    settings: SubscriptionSettings,
+}
+
+impl Subscriptions {
+    pub fn new(event_repo: Arc<dyn EventStore>, subscribers: Vec<Subscriber>, settings: SubscriptionSettings) -> Subscriptions {
+        Subscriptions {
+            event_repo,
+            subscribers: subscribers
+                .into_iter()
+                .map(|subscriber| Arc::new(RunningSubscriber {
+                    subscriber,
+                    state: Mutex::new(SubscriptionState {
+                        position: 0,
+                        running: false,
+                        last_handled_at: None,
+                        last_error: None,
+                    }),
+                }))
+                .collect(),
+            settings,
+        }
+    }
+
+    /// Spawns one task per subscriber. `wakeups` is called once per subscriber for a receiver
+    /// of newly persisted changes.
+    pub fn start(&self, wakeups: impl Fn() -> Receiver<TodoChange>, shutdown: Shutdown) {
+        for subscriber in &self.subscribers {
+            tokio::spawn(subscriber.clone().run(self.event_repo.clone(), wakeups(), shutdown.clone(), self.settings));
+        }
+    }
+
+    pub async fn status(&self) -> EventStoreResult<Vec<SubscriptionStatus>> {
+        let last_position = self.event_repo.last_position().await?;
+
+        Ok(self.subscribers
+            .iter()
+            .map(|subscriber| {
+                let state = subscriber.state();
+                SubscriptionStatus {
+                    name: subscriber.subscriber.name.clone(),
+                    position: state.position,
+                    lag: last_position.saturating_sub(state.position),
+                    running: state.running,
+                    last_handled_at: state.last_handled_at,
+                    last_error: state.last_error.clone(),
+                }
+            })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+    use serde_json::Value;
+    use crate::guid::Guid;
+    use crate::services::aggregate::{Aggregate, TodoAggregate, TodoEvent};
+    use crate::services::event_store::{EventEnvelope, EventMetadata, EventStore, InMemoryEventStore, RecordedEvent};
+    use crate::services::subscription::{EventHandler, Subscriber, Subscriptions, SubscriptionSettings};
+
+    struct Collect(Arc<Mutex<Vec<u64>>>);
+
+    #[async_trait]
+    impl EventHandler for Collect {
+        async fn handle(&self, event: &RecordedEvent<Value>) -> Result<(), String> {
+            self.0.lock().unwrap().push(event.position);
+            Ok(())
+        }
+    }
+
+    fn subscriptions(event_repo: Arc<dyn EventStore>, seen: Arc<Mutex<Vec<u64>>>) -> Subscriptions {
+        let settings = SubscriptionSettings { batch_size: 2, poll_interval: Duration::from_secs(1) };
+        Subscriptions::new(event_repo, vec![Subscriber::new("collect", Box::new(Collect(seen)))], settings)
+    }
+
+    async fn create(event_repo: &dyn EventStore) {
+        let id = Guid::new();
+        let event = EventEnvelope::new(TodoEvent::Create { name: "a".to_string(), id }, &EventMetadata::default());
+        event_repo.append(&TodoAggregate::stream_name(id), 0, vec![event.to_raw().unwrap()]).await.unwrap();
+    }
+
+    #[rocket::async_test]
+    async fn resumes_from_the_saved_checkpoint() {
+        let event_repo: Arc<dyn EventStore> = Arc::new(InMemoryEventStore::default());
+        let seen = Arc::new(Mutex::new(vec![]));
+        for _ in 0..3 {
+            create(event_repo.as_ref()).await;
+        }
+
+        let first_run = subscriptions(event_repo.clone(), seen.clone());
+        let handled = first_run.subscribers[0].catch_up(event_repo.as_ref(), 2).await.unwrap();
+        assert_eq!(handled, 3);
+
+        create(event_repo.as_ref()).await;
+        let status = first_run.status().await.unwrap();
+        assert_eq!((status[0].position, status[0].lag), (3, 1));
+
+        // A new instance stands in for a restarted process.
+        let second_run = subscriptions(event_repo.clone(), seen.clone());
+        second_run.subscribers[0].catch_up(event_repo.as_ref(), 2).await.unwrap();
+        assert_eq!(*seen.lock().unwrap(), vec![1, 2, 3, 4]);
+        assert_eq!(event_repo.get_checkpoint("collect").await.unwrap(), Some(4));
+    }
+}
@@ -1,103 +1,996 @@
+use std::collections::{HashMap, HashSet};
+use std::collections::hash_map::Entry;
 use std::error::Error;
 use std::fmt::{Display, Formatter};
 use std::string::ToString;
-use serde_derive::Serialize;
+use std::sync::Arc;
+use chrono::{DateTime, Duration, Utc};
 use crate::guid::Guid;
-use crate::routes::todo::{Todo};
-use crate::services::aggregate::{Aggregate, AggregateErr, TodoAggregate, TodoEvent};
-use crate::services::data::{DataAccessErr, TodoRepository};
-use crate::services::event_store::{TodoEventColl, TodoEventCollRepo};
+use crate::routes::todo::{Status, Todo, TodoTree};
+use crate::services::aggregate::{can_change_status, Aggregate, AggregateErr, DomainError, TodoAggregate, TodoCommand, TodoEvent};
+use crate::services::clock::Clock;
+use crate::services::data::{DataAccessErr, DataAccessErrKind, TodoRepository};
+use crate::services::event_store::{EventEnvelope, EventMetadata, EventStore, EventStoreErr, RecordedEvent};
+use crate::services::feed::{ChangeFeed, TodoChange};
+use crate::services::list::{TodoList, TodoListCommand};
+use crate::services::projection::TodoProjection;
+use crate::services::repository::EventSourcedRepository;
+use crate::services::trash::{TrashPurger, TrashSettings};
+use rocket::tokio::sync::broadcast::Receiver;
+use serde_json::Value;
 
-#[derive(Debug, Serialize)]
-pub struct TodoServiceErr {
-    message: String,
+#[derive(Debug)]
+pub enum TodoServiceErr {
+    NotFound(String),
+    /// Another update was stored between reading the todo and appending to it, or the
+    /// request was based on an outdated version.
+    Conflict(String),
+    Deleted(String),
+    Validation(String),
+    /// The command is well-formed but the todo's current state does not allow it.
+    Rejected(String),
+    StorageUnavailable(String),
+    /// A cascade stopped part of the way; the todos in `completed` stay complete.
+    PartiallyApplied { message: String, completed: Vec<Guid> },
 }
 
 impl TodoServiceErr {
-    pub fn new(message: String) -> TodoServiceErr {
-        TodoServiceErr {
-            message
+    /// Stable, machine-readable identifier of the kind of failure.
+    pub fn code(&self) -> &'static str {
+        match self {
+            TodoServiceErr::NotFound(_) => { "not_found" }
+            TodoServiceErr::Conflict(_) => { "conflict" }
+            TodoServiceErr::Deleted(_) => { "deleted" }
+            TodoServiceErr::Validation(_) => { "validation_failed" }
+            TodoServiceErr::Rejected(_) => { "command_rejected" }
+            TodoServiceErr::StorageUnavailable(_) => { "storage_unavailable" }
+            TodoServiceErr::PartiallyApplied { .. } => { "partially_applied" }
+        }
+    }
+
+    pub fn message(&self) -> &str {
+        match self {
+            TodoServiceErr::NotFound(message)
+            | TodoServiceErr::Conflict(message)
+            | TodoServiceErr::Deleted(message)
+            | TodoServiceErr::Validation(message)
+            | TodoServiceErr::Rejected(message)
+            | TodoServiceErr::StorageUnavailable(message)
+            | TodoServiceErr::PartiallyApplied { message, .. } => { message.as_str() }
         }
     }
 }
 
 impl Display for TodoServiceErr {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        f.write_str(self.message.as_str())
+        f.write_str(self.message())
+    }
+}
+
+impl From<AggregateErr> for TodoServiceErr {
+    fn from(err: AggregateErr) -> TodoServiceErr {
+        match err {
+            AggregateErr::ConcurrencyErr => { TodoServiceErr::Conflict(err.to_string()) }
+            AggregateErr::Deleted => { TodoServiceErr::Deleted(err.to_string()) }
+            AggregateErr::InvalidTransition { .. } => { TodoServiceErr::Rejected(err.to_string()) }
+            AggregateErr::NotDeleted => { TodoServiceErr::Rejected(err.to_string()) }
+        }
+    }
+}
+
+impl From<DomainError> for TodoServiceErr {
+    fn from(err: DomainError) -> TodoServiceErr {
+        match err {
+            DomainError::Deleted => { TodoServiceErr::Deleted(err.to_string()) }
+            DomainError::EmptyName => { TodoServiceErr::Validation(err.to_string()) }
+            DomainError::AlreadyExists => { TodoServiceErr::Conflict(err.to_string()) }
+            _ => { TodoServiceErr::Rejected(err.to_string()) }
+        }
+    }
+}
+
+impl From<DataAccessErr> for TodoServiceErr {
+    fn from(err: DataAccessErr) -> TodoServiceErr {
+        match err.kind {
+            DataAccessErrKind::NotFound => { TodoServiceErr::NotFound(err.message) }
+            DataAccessErrKind::Unavailable => { TodoServiceErr::StorageUnavailable(err.message) }
+        }
+    }
+}
+
+impl From<EventStoreErr> for TodoServiceErr {
+    fn from(err: EventStoreErr) -> TodoServiceErr {
+        match err {
+            EventStoreErr::ConcurrencyErr { .. } => { TodoServiceErr::Conflict(err.to_string()) }
+            EventStoreErr::NotFound(_) => { TodoServiceErr::NotFound(err.to_string()) }
+            EventStoreErr::StorageErr(message) => { TodoServiceErr::StorageUnavailable(message) }
+        }
     }
 }
 
 impl Error for TodoServiceErr {}
 
-pub struct TodoService {
-    todo_repo: Box<dyn TodoRepository>,
-    event_repo: TodoEventCollRepo,
+/// A past moment a todo can be read at.
+#[derive(Debug, Clone, Copy)]
+pub enum PointInTime {
+    /// Right after the event with this version was applied.
+    Version(u32),
+    /// After every event recorded at or before this time was applied.
+    Timestamp(DateTime<Utc>),
 }
 
-// const MAP_DATA_ERR: fn(DataAccessErr) -> TodoServiceErr = |x: DataAccessErr| TodoServiceErr::new(x.message);
-const MAP_STRING_ERR: fn(String) -> TodoServiceErr = |message: String| TodoServiceErr { message };
-const MAP_AGG_ERR: fn(AggregateErr) -> TodoServiceErr = |x: AggregateErr| TodoServiceErr {message: x.to_string()};
+impl PointInTime {
+    fn includes(&self, envelope: &EventEnvelope<TodoEvent>) -> bool {
+        match self {
+            PointInTime::Version(version) => { envelope.version() <= *version }
+            PointInTime::Timestamp(timestamp) => { envelope.recorded_at <= *timestamp }
+        }
+    }
+}
+
+/// Narrows `list_tasks` down by due date. Every condition that is set has to hold.
+#[derive(Debug, Clone, Default)]
+pub struct TodoFilter {
+    pub due_before: Option<DateTime<Utc>>,
+    pub overdue: Option<bool>,
+    /// Due from now until this much later.
+    pub due_within: Option<Duration>,
+}
+
+impl TodoFilter {
+    fn matches(&self, todo: &Todo, now: DateTime<Utc>) -> bool {
+        self.due_before.is_none_or(|before| todo.due.is_some_and(|due| due < before))
+            && self.overdue.is_none_or(|overdue| todo.overdue == overdue)
+            && self.due_within.is_none_or(|within| todo.due.is_some_and(|due| due >= now && due <= now + within))
+    }
+}
+
+/// Snapshot every this many events unless configured otherwise.
+pub const DEFAULT_SNAPSHOT_INTERVAL: u32 = 50;
+/// How many events a read of the global log returns unless asked for fewer.
+pub const DEFAULT_BATCH_SIZE: usize = 100;
+pub const MAX_BATCH_SIZE: usize = 1000;
+/// How often a list membership change is tried while other writers keep changing the list.
+const MEMBERSHIP_ATTEMPTS: u32 = 5;
+
+pub struct TodoService {
+    todo_repo: Arc<dyn TodoRepository>,
+    todos: EventSourcedRepository<TodoAggregate>,
+    lists: EventSourcedRepository<TodoList>,
+    projection: TodoProjection,
+    feed: ChangeFeed,
+    clock: Box<dyn Clock>,
+}
 
 impl TodoService {
-    pub async fn init(todo_repo: Box<dyn TodoRepository>, event_repo: TodoEventCollRepo) -> TodoService {
+    /// A snapshot of a todo is stored whenever it passes a multiple of `snapshot_interval`.
+    /// 0 disables snapshots.
+    pub async fn init(todo_repo: Box<dyn TodoRepository>, event_repo: Box<dyn EventStore>, snapshot_interval: u32, clock: Box<dyn Clock>) -> TodoService {
+        let todo_repo: Arc<dyn TodoRepository> = Arc::from(todo_repo);
+        let event_repo: Arc<dyn EventStore> = Arc::from(event_repo);
+        let todos = EventSourcedRepository::new(event_repo.clone(), snapshot_interval);
         TodoService {
+            lists: EventSourcedRepository::new(event_repo, snapshot_interval),
+            projection: TodoProjection::new(todo_repo.clone(), todos.clone()),
             todo_repo,
-            event_repo,
+            todos,
+            feed: ChangeFeed::new(),
+            clock,
         }
     }
 
-    pub async fn list_tasks(&self) -> Result<Vec<TodoAggregate>, TodoServiceErr> {
-        self.event_repo
-            .list().await
-            .map_err(MAP_STRING_ERR)
-            .map(|events| {
-                events
-                    .into_iter()
-                    .map(|event| event.to_agg())
-                    .collect()
-            })
+    /// `todo` with `overdue` worked out at the current time.
+    fn deadline_aware(&self, todo: Todo) -> Todo {
+        Todo {
+            overdue: todo.is_overdue(self.clock.now()),
+            ..todo
+        }
     }
 
-    pub async fn get_task_by_id(&self, id: Guid) -> Result<TodoAggregate, TodoServiceErr> {
-        self.event_repo
-            .get(id).await
-            .map_err(MAP_STRING_ERR)
-            .map(|events| events.to_agg())
+    /// The todo as clients see it.
+    pub fn todo(&self, agg: TodoAggregate) -> Todo {
+        self.deadline_aware(Todo::from_agg(agg))
     }
 
-    pub async fn update_task(&self, id:Guid, event: TodoEvent) -> Result<TodoAggregate, TodoServiceErr> {
-        let mut coll = self.event_repo
-            .get(id).await
-            .map_err(MAP_STRING_ERR)?;
-        let mut agg = coll.to_agg();
-        
-        let valid_event = agg
-            .try_apply(event)
-            .map_err(MAP_AGG_ERR)?;
-        agg = agg.apply(&valid_event);
-        coll = coll.add_event(valid_event);
-        
-        self.event_repo
-            .update(&coll).await
-            .map_err(MAP_STRING_ERR)?;
-        
+    async fn load(&self, id: Guid) -> Result<TodoAggregate, TodoServiceErr> {
+        self.todos
+            .load(id).await
+            .map_err(TodoServiceErr::from)
+    }
+
+    async fn project(&self, id: Guid, envelope: EventEnvelope<TodoEvent>) {
+        // The projection subscriber projects every event again, so a failed projection is
+        // logged rather than failing a request whose events are already stored.
+        if let Err(err) = self.projection.project(id, &[envelope]).await {
+            log::warn!("Could not project events of todo {}: {}", id, err);
+        }
+    }
+
+    async fn persisted(&self, agg: &TodoAggregate, recorded: Vec<RecordedEvent<TodoEvent>>) {
+        for recorded in recorded {
+            self.project(agg.id, recorded.event.clone()).await;
+            self.feed.publish(TodoChange {
+                position: recorded.position,
+                event: recorded.event,
+                todo: self.todo(agg.clone()),
+            });
+        }
+    }
+
+    pub fn event_store(&self) -> Arc<dyn EventStore> {
+        self.todos.event_store()
+    }
+
+    /// Receives every change persisted from now on.
+    pub fn subscribe(&self) -> Receiver<TodoChange> {
+        self.feed.subscribe()
+    }
+
+    /// Position of the newest stored event.
+    pub async fn last_position(&self) -> Result<u64, TodoServiceErr> {
+        self.todos
+            .event_store()
+            .last_position().await
+            .map_err(TodoServiceErr::from)
+    }
+
+    /// Reads a batch of the global log after `after_position`, with the events of every
+    /// aggregate type.
+    pub async fn read_all(&self, after_position: u64, limit: Option<usize>) -> Result<Vec<RecordedEvent<Value>>, TodoServiceErr> {
+        let limit = limit.unwrap_or(DEFAULT_BATCH_SIZE);
+        if limit == 0 || limit > MAX_BATCH_SIZE {
+            return Err(TodoServiceErr::Validation(format!("limit must be between 1 and {}", MAX_BATCH_SIZE)));
+        }
+
+        self.todos
+            .event_store()
+            .read_all(after_position, limit).await
+            .map_err(TodoServiceErr::from)
+    }
+
+    /// Rebuilds the todo changes after `after_position` from the event store, each with the
+    /// todo as it was right after that event.
+    pub async fn changes_after(&self, after_position: u64, limit: usize) -> Result<Vec<TodoChange>, TodoServiceErr> {
+        let recorded = self.todos.read(after_position, limit).await?;
+        let mut streams: HashMap<Guid, Vec<EventEnvelope<TodoEvent>>> = HashMap::new();
+        let mut changes = vec![];
+
+        for recorded in recorded {
+            let id = EventSourcedRepository::<TodoAggregate>::id_of(&recorded.stream)?;
+            let envelopes = match streams.entry(id) {
+                Entry::Occupied(entry) => { entry.into_mut() }
+                Entry::Vacant(entry) => { entry.insert(self.todos.history(id).await?) }
+            };
+            let events = envelopes
+                .iter()
+                .take_while(|envelope| envelope.version() <= recorded.version)
+                .map(|envelope| envelope.event.clone())
+                .collect();
+
+            changes.push(TodoChange {
+                position: recorded.position,
+                event: recorded.event,
+                todo: self.todo(TodoAggregate::from_events(events)),
+            });
+        }
+
+        Ok(changes)
+    }
+
+    pub async fn list_tasks(&self, filter: &TodoFilter) -> Result<Vec<Todo>, TodoServiceErr> {
+        let now = self.clock.now();
+
+        Ok(self.todo_repo
+            .list().await?
+            .into_iter()
+            .map(|todo| self.deadline_aware(todo))
+            .filter(|todo| filter.matches(todo, now))
+            .collect())
+    }
+
+    pub async fn get_task_by_id(&self, id: Guid) -> Result<Todo, TodoServiceErr> {
+        let todo = self.todo_repo.get_by_id(id).await?;
+        if todo.deleted_at.is_some() {
+            return Err(TodoServiceErr::Deleted("Todo was deleted".to_string()));
+        }
+
+        Ok(self.deadline_aware(todo))
+    }
+
+    /// Replays the events of a todo up to `at`, ignoring snapshots and the read model, so the
+    /// result is exactly what a reader would have seen at that point. Events stored before
+    /// they carried a timestamp count as recorded at the Unix epoch.
+    pub async fn get_task_at(&self, id: Guid, at: PointInTime) -> Result<TodoAggregate, TodoServiceErr> {
+        let history = self.todos.history(id).await?;
+        if let PointInTime::Version(version) = at {
+            let last_version = history.last().map_or(0, |envelope| envelope.version());
+            if version > last_version {
+                return Err(TodoServiceErr::NotFound(format!("Todo has not reached version {} yet", version)));
+            }
+        }
+        let events: Vec<TodoEvent> = history
+            .into_iter()
+            .take_while(|envelope| at.includes(envelope))
+            .map(|envelope| envelope.event)
+            .collect();
+
+        if events.is_empty() {
+            return Err(TodoServiceErr::NotFound("Todo did not exist yet at the requested point".to_string()));
+        }
+        let agg = TodoAggregate::from_events(events);
+        if agg.is_deleted {
+            return Err(TodoServiceErr::Deleted("Todo was already deleted at the requested point".to_string()));
+        }
+
+        Ok(agg)
+    }
+
+    /// Returns the events of a todo in the order they were stored, limited to the inclusive
+    /// `from_version`..=`to_version` range when bounds are given.
+    pub async fn get_history(&self, id: Guid, from_version: Option<u32>, to_version: Option<u32>) -> Result<Vec<EventEnvelope<TodoEvent>>, TodoServiceErr> {
+        let from_version = from_version.unwrap_or(1);
+        let to_version = to_version.unwrap_or(u32::MAX);
+        if from_version > to_version {
+            return Err(TodoServiceErr::Validation("from_version must not be greater than to_version".to_string()));
+        }
+
+        Ok(self.todos
+            .history(id).await?
+            .into_iter()
+            .filter(|envelope| (from_version..=to_version).contains(&envelope.version()))
+            .collect())
+    }
+
+    /// The projection, to be run as the `PROJECTION_SUBSCRIBER`.
+    pub fn projection(&self) -> TodoProjection {
+        self.projection.clone()
+    }
+
+    pub async fn rebuild_projection(&self) -> Result<usize, TodoServiceErr> {
+        self.projection
+            .rebuild().await
+            .map_err(TodoServiceErr::from)
+    }
+
+    /// Carries out `command` on todo `id`. With `expected_version` the command is only
+    /// accepted while the todo is still at that version.
+    pub async fn update_task(&self, id: Guid, command: TodoCommand, expected_version: Option<u32>, metadata: &EventMetadata) -> Result<TodoAggregate, TodoServiceErr> {
+        let mut completed = vec![];
+        match &command {
+            TodoCommand::CompleteTodo { cascade } => { completed = self.complete_subtasks(id, *cascade, expected_version, metadata).await?; }
+            TodoCommand::AttachToParent { parent } => { self.check_parent(id, *parent).await?; }
+            _ => {}
+        }
+        let deleting = command == TodoCommand::DeleteTodo;
+        let restoring = command == TodoCommand::RestoreTodo;
+        let (agg, recorded) = self.todos
+            .execute(id, metadata, |agg: &TodoAggregate| {
+                at_expected_version(agg, expected_version)?;
+                Ok::<_, TodoServiceErr>(agg.decide(command)?)
+            }).await
+            .map_err(|err| partially_applied(err, completed))?;
+        self.persisted(&agg, recorded).await;
+        if deleting {
+            if let Some(list) = agg.list {
+                follow_up(id, "remove it from its list", self.change_membership(list, TodoListCommand::RemoveTodo { todo: id }, metadata).await);
+            }
+            // Subtasks outlive their parent as top-level todos.
+            match self.subtasks().await {
+                Ok(mut subtasks) => {
+                    for subtask in subtasks.remove(&id).unwrap_or_default() {
+                        follow_up(id, "detach its subtasks", self.run(subtask.id, TodoCommand::DetachFromParent, metadata).await);
+                    }
+                }
+                Err(err) => { follow_up(id, "detach its subtasks", Err::<(), _>(err)); }
+            }
+        }
+        if restoring {
+            return Ok(self.reconcile_restored(agg, metadata).await);
+        }
+
+        Ok(agg)
+    }
+
+    /// Puts a restored todo back into its list and under its parent, or takes it out of
+    /// them when the list was archived or the parent is gone in the meantime.
+    /// The restore itself is already stored, so a step that fails here is logged.
+    async fn reconcile_restored(&self, mut agg: TodoAggregate, metadata: &EventMetadata) -> TodoAggregate {
+        let id = agg.id;
+        if let Some(list) = agg.list {
+            match self.change_membership(list, TodoListCommand::AddTodo { todo: id }, metadata).await {
+                Ok(()) => {}
+                Err(TodoServiceErr::Rejected(_)) => {
+                    match self.run(id, TodoCommand::MoveTodo { list: None }, metadata).await {
+                        Ok(moved) => { agg = moved; }
+                        Err(err) => { follow_up(id, "take it out of its archived list", Err::<(), _>(err)); }
+                    }
+                }
+                Err(err) => { follow_up(id, "put it back into its list", Err::<(), _>(err)); }
+            }
+        }
+        if let Some(parent) = agg.parent {
+            let gone = match self.load(parent).await {
+                Ok(parent) => { parent.is_deleted || parent.version() == 0 }
+                Err(TodoServiceErr::NotFound(_)) => { true }
+                Err(err) => {
+                    follow_up(id, "look up its parent", Err::<(), _>(err));
+                    false
+                }
+            };
+            if gone {
+                match self.run(id, TodoCommand::DetachFromParent, metadata).await {
+                    Ok(detached) => { agg = detached; }
+                    Err(err) => { follow_up(id, "detach it from its deleted parent", Err::<(), _>(err)); }
+                }
+            }
+        }
+
+        agg
+    }
+
+    /// Deleted todos that can still be restored, most recently deleted first.
+    pub async fn trash(&self) -> Result<Vec<Todo>, TodoServiceErr> {
+        let mut trash: Vec<Todo> = self.todo_repo
+            .list_deleted().await?
+            .into_iter()
+            .map(|todo| self.deadline_aware(todo))
+            .collect();
+        trash.sort_by_key(|todo| std::cmp::Reverse(todo.deleted_at));
+
+        Ok(trash)
+    }
+
+    /// A purger for the deleted todos of this service.
+    pub fn purger(&self, settings: TrashSettings, clock: Box<dyn Clock>) -> TrashPurger {
+        TrashPurger::new(self.todo_repo.clone(), self.todos.clone(), settings, clock)
+    }
+
+    /// Carries out a command the service issues on its own behalf.
+    async fn run(&self, id: Guid, command: TodoCommand, metadata: &EventMetadata) -> Result<TodoAggregate, TodoServiceErr> {
+        let (agg, recorded) = self.todos
+            .execute(id, metadata, |agg: &TodoAggregate| Ok::<_, TodoServiceErr>(agg.decide(command)?)).await?;
+        self.persisted(&agg, recorded).await;
+
         Ok(agg)
     }
 
-    pub async fn create_task(&self, name: String) -> Result<TodoAggregate, TodoServiceErr> {
+    /// Rejects making `id` a subtask of `parent` if `parent` does not exist or is `id` itself
+    /// or one of its subtasks.
+    async fn check_parent(&self, id: Guid, parent: Guid) -> Result<(), TodoServiceErr> {
+        let mut ancestor = match self.load(parent).await {
+            Ok(agg) if !agg.is_deleted => { agg }
+            Ok(_) | Err(TodoServiceErr::NotFound(_)) => {
+                return Err(TodoServiceErr::Validation("Parent todo does not exist".to_string()));
+            }
+            Err(err) => { return Err(err); }
+        };
+        let mut seen = HashSet::new();
+        loop {
+            if ancestor.id == id {
+                return Err(DomainError::Cycle.into());
+            }
+            // Guards against cycles that concurrent attaches slipped past this check.
+            match ancestor.parent {
+                Some(next) if seen.insert(next) => { ancestor = self.load(next).await?; }
+                _ => { return Ok(()); }
+            }
+        }
+    }
+
+    /// Completes the open subtasks of `id` when asked to cascade, and rejects completing a
+    /// todo with open subtasks otherwise.
+    async fn complete_subtasks(&self, id: Guid, cascade: bool, expected_version: Option<u32>, metadata: &EventMetadata) -> Result<Vec<Guid>, TodoServiceErr> {
+        let open = open_subtasks(id, &mut self.subtasks().await?);
+        if open.is_empty() {
+            return Ok(vec![]);
+        }
+        if !cascade {
+            return Err(DomainError::OpenSubtasks.into());
+        }
+
+        // The todo itself and every subtask have to accept the command before anything
+        // below it changes. The read model may lag, so the subtasks are checked against
+        // their streams and completed only at the version that was checked.
+        let agg = self.load(id).await?;
+        at_expected_version(&agg, expected_version)?;
+        agg.decide(TodoCommand::CompleteTodo { cascade })?;
+        if !can_change_status(&agg.status, &Status::Complete) {
+            return Err(AggregateErr::InvalidTransition { from: agg.status, to: Status::Complete }.into());
+        }
+        let mut pending = vec![];
+        for (subtask, _) in open {
+            let subtask = self.load(subtask).await?;
+            if subtask.status.is_done() {
+                continue;
+            }
+            if !can_change_status(&subtask.status, &Status::Complete) {
+                return Err(AggregateErr::InvalidTransition { from: subtask.status, to: Status::Complete }.into());
+            }
+            subtask.decide(TodoCommand::CompleteTodo { cascade: false })?;
+            pending.push((subtask.id, subtask.version()));
+        }
+
+        let mut completed = vec![];
+        for (subtask, version) in pending {
+            let (agg, recorded) = self.todos
+                .execute(subtask, metadata, |agg: &TodoAggregate| {
+                    at_expected_version(agg, Some(version))?;
+                    Ok::<_, TodoServiceErr>(agg.decide(TodoCommand::CompleteTodo { cascade: false })?)
+                }).await
+                .map_err(|err| partially_applied(err, completed.clone()))?;
+            self.persisted(&agg, recorded).await;
+            completed.push(subtask);
+        }
+
+        Ok(completed)
+    }
+
+    /// Subtasks in the read model by the id of their parent.
+    async fn subtasks(&self) -> Result<HashMap<Guid, Vec<Todo>>, TodoServiceErr> {
+        let mut subtasks: HashMap<Guid, Vec<Todo>> = HashMap::new();
+        for todo in self.todo_repo.list().await? {
+            if let Some(parent) = todo.parent {
+                subtasks.entry(parent).or_default().push(self.deadline_aware(todo));
+            }
+        }
+
+        Ok(subtasks)
+    }
+
+    pub async fn get_task_tree(&self, id: Guid) -> Result<TodoTree, TodoServiceErr> {
+        let todo = self.get_task_by_id(id).await?;
+
+        Ok(tree(todo, &mut self.subtasks().await?).0)
+    }
+
+    pub async fn create_task(&self, name: String, metadata: &EventMetadata) -> Result<TodoAggregate, TodoServiceErr> {
         let id = Guid::new();
-        let event = TodoEvent::Create { name, id: id.clone() };
-        let mut coll = TodoEventColl::new(id.clone());
-        let mut agg = TodoAggregate::new();
-        
-        let valid_event = agg.try_apply(event).map_err(MAP_AGG_ERR)?;
-        agg = agg.apply(&valid_event);
-        coll = coll.add_event(valid_event);
-        
-        self.event_repo
-            .insert(&coll).await
-            .map_err(MAP_STRING_ERR)?;
+        let (agg, recorded) = self.todos
+            .create(id, metadata, |agg: &TodoAggregate| Ok::<_, TodoServiceErr>(agg.decide(TodoCommand::CreateTodo { id, name })?)).await?;
+        self.persisted(&agg, recorded).await;
         
         Ok(agg)
     }
+
+    pub async fn list_lists(&self) -> Result<Vec<TodoList>, TodoServiceErr> {
+        Ok(self.lists
+            .list().await?
+            .into_iter()
+            .map(|(_, envelopes)| TodoList::from_events(envelopes.into_iter().map(|envelope| envelope.event).collect()))
+            .collect())
+    }
+
+    pub async fn get_list(&self, id: Guid) -> Result<TodoList, TodoServiceErr> {
+        let list = self.lists.load(id).await?;
+        if list.version() == 0 {
+            return Err(TodoServiceErr::NotFound("List not found".to_string()));
+        }
+
+        Ok(list)
+    }
+
+    /// The todos of list `id` in the order they joined it.
+    pub async fn list_todos(&self, id: Guid) -> Result<Vec<Todo>, TodoServiceErr> {
+        let list = self.get_list(id).await?;
+        let mut todos: HashMap<Guid, Todo> = self.todo_repo
+            .list().await?
+            .into_iter()
+            .filter(|todo| todo.list == Some(id))
+            .map(|todo| (todo.id, self.deadline_aware(todo)))
+            .collect();
+
+        Ok(list.todos.iter().filter_map(|member| todos.remove(member)).collect())
+    }
+
+    pub async fn create_list(&self, name: String, metadata: &EventMetadata) -> Result<TodoList, TodoServiceErr> {
+        let id = Guid::new();
+        let (list, _) = self.lists
+            .create(id, metadata, |list: &TodoList| Ok::<_, TodoServiceErr>(list.decide(TodoListCommand::CreateList { id, name })?)).await?;
+
+        Ok(list)
+    }
+
+    /// Carries out `command` on list `id`, like `update_task` does for todos.
+    pub async fn update_list(&self, id: Guid, command: TodoListCommand, expected_version: Option<u32>, metadata: &EventMetadata) -> Result<TodoList, TodoServiceErr> {
+        let (list, _) = self.lists
+            .execute(id, metadata, |list: &TodoList| {
+                match expected_version {
+                    Some(version) if version != list.version() => {
+                        Err(TodoServiceErr::Conflict(format!("List is at version {}, not {}", list.version(), version)))
+                    }
+                    _ => { Ok(list.decide(command)?) }
+                }
+            }).await?;
+
+        Ok(list)
+    }
+
+    /// Moves todo `id` into list `to`, or out of its list when `to` is `None`. The todo and
+    /// both lists each record their side of the move. The new list takes the todo first, so
+    /// an archived or missing list rejects the move before anything else changes.
+    pub async fn move_task(&self, id: Guid, to: Option<Guid>, metadata: &EventMetadata) -> Result<TodoAggregate, TodoServiceErr> {
+        // Fails early for missing todos and moves to the list the todo is already in.
+        self.load(id).await?.decide(TodoCommand::MoveTodo { list: to })?;
+        if let Some(list) = to {
+            self.change_membership(list, TodoListCommand::AddTodo { todo: id }, metadata).await?;
+        }
+
+        let mut from = None;
+        let moved = self.todos
+            .execute(id, metadata, |agg: &TodoAggregate| {
+                from = agg.list;
+                Ok::<_, TodoServiceErr>(agg.decide(TodoCommand::MoveTodo { list: to })?)
+            }).await;
+        let (agg, recorded) = match moved {
+            Ok(moved) => { moved }
+            Err(err) => {
+                if let Some(list) = to {
+                    follow_up(id, "take it back out of the list it was moving to", self.change_membership(list, TodoListCommand::RemoveTodo { todo: id }, metadata).await);
+                }
+                return Err(err);
+            }
+        };
+        self.persisted(&agg, recorded).await;
+
+        if let Some(list) = from {
+            follow_up(id, "remove it from its old list", self.change_membership(list, TodoListCommand::RemoveTodo { todo: id }, metadata).await);
+        }
+
+        Ok(agg)
+    }
+
+    /// Takes todo `id` out of list `list`.
+    pub async fn remove_from_list(&self, list: Guid, id: Guid, metadata: &EventMetadata) -> Result<TodoAggregate, TodoServiceErr> {
+        if self.load(id).await?.list != Some(list) {
+            return Err(DomainError::NotInList.into());
+        }
+
+        self.move_task(id, None, metadata).await
+    }
+
+    /// Membership changes are driven by the todo, which has already been checked, so a
+    /// concurrent change to the list is retried rather than reported.
+    async fn change_membership(&self, list: Guid, command: TodoListCommand, metadata: &EventMetadata) -> Result<(), TodoServiceErr> {
+        let mut attempts = 0;
+        loop {
+            attempts += 1;
+            match self.update_list(list, command.clone(), None, metadata).await {
+                Err(TodoServiceErr::Conflict(_)) if attempts < MEMBERSHIP_ATTEMPTS => {}
+                result => { return result.map(|_| ()); }
+            }
+        }
+    }
+}
+
+/// Reports `err` as a partially applied cascade once any of the `completed` subtasks was
+/// stored, since those changes are not undone.
+fn partially_applied(err: TodoServiceErr, completed: Vec<Guid>) -> TodoServiceErr {
+    if completed.is_empty() {
+        return err;
+    }
+
+    TodoServiceErr::PartiallyApplied {
+        message: format!("Completed {} subtasks before failing: {}", completed.len(), err),
+        completed,
+    }
+}
+
+/// Logs a step that failed after the change to todo `id` itself was stored. That change
+/// stands, so the request still succeeds.
+fn follow_up<T>(id: Guid, step: &str, result: Result<T, TodoServiceErr>) {
+    if let Err(err) = result {
+        log::warn!("Could not {} after changing todo {}: {}", step, id, err);
+    }
+}
+
+fn at_expected_version(agg: &TodoAggregate, expected_version: Option<u32>) -> Result<(), TodoServiceErr> {
+    match expected_version {
+        Some(version) if version != agg.version() => {
+            Err(TodoServiceErr::Conflict(format!("Todo is at version {}, not {}", agg.version(), version)))
+        }
+        _ => { Ok(()) }
+    }
+}
+
+/// Every todo below `id` that is not done yet with its status, each after its own subtasks.
+/// Visited subtasks are taken out of `subtasks`, so a cycle cannot recurse forever.
+fn open_subtasks(id: Guid, subtasks: &mut HashMap<Guid, Vec<Todo>>) -> Vec<(Guid, Status)> {
+    let mut open = vec![];
+    for subtask in subtasks.remove(&id).unwrap_or_default() {
+        open.extend(open_subtasks(subtask.id, subtasks));
+        if !subtask.status.is_done() {
+            open.push((subtask.id, subtask.status));
+        }
+    }
+
+    open
+}
+
+/// The tree below `todo` together with its completion as a fraction. Cancelled subtasks do
+/// not count towards the completion.
+fn tree(todo: Todo, subtasks: &mut HashMap<Guid, Vec<Todo>>) -> (TodoTree, Option<f64>) {
+    let children: Vec<(TodoTree, Option<f64>)> = subtasks
+        .remove(&todo.id)
+        .unwrap_or_default()
+        .into_iter()
+        .map(|subtask| tree(subtask, subtasks))
+        .collect();
+    let counted: Vec<f64> = children
+        .iter()
+        .filter(|(child, _)| child.todo.status != Status::Cancelled)
+        .map(|(child, completion)| if child.todo.status == Status::Complete { 1.0 } else { completion.unwrap_or(0.0) })
+        .collect();
+    let completion = if counted.is_empty() {
+        None
+    } else {
+        Some(counted.iter().sum::<f64>() / counted.len() as f64)
+    };
+
+    let tree = TodoTree {
+        todo,
+        completion: completion.map(|completion| (completion * 100.0).round() as u8),
+        children: children.into_iter().map(|(child, _)| child).collect(),
+    };
+    (tree, completion)
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{DateTime, Duration, TimeZone, Utc};
+    use crate::guid::Guid;
+    use crate::services::aggregate::{Aggregate, TodoAggregate, TodoCommand, TodoEvent};
+    use crate::services::clock::{Clock, FixedClock, SystemClock};
+    use crate::services::data::InMemoryTodoRepository;
+    use crate::services::event_store::{EventEnvelope, EventMetadata, EventStoreErr, InMemoryEventStore};
+    use crate::routes::todo::Status;
+    use crate::services::list::TodoListCommand;
+    use crate::services::todo::{PointInTime, TodoFilter, TodoService, TodoServiceErr};
+
+    async fn service(clock: Box<dyn Clock>) -> TodoService {
+        TodoService::init(
+            Box::new(InMemoryTodoRepository::new()),
+            Box::new(InMemoryEventStore::default()),
+            0,
+            clock,
+        ).await
+    }
+
+    #[rocket::async_test]
+    async fn snapshot_load_matches_full_replay() {
+        let service = TodoService::init(
+            Box::new(InMemoryTodoRepository::new()),
+            Box::new(InMemoryEventStore::default()),
+            10,
+            Box::new(SystemClock),
+        ).await;
+        let metadata = EventMetadata::default();
+        let id = service.create_task("snapshot me".to_string(), &metadata).await.unwrap().id;
+
+        for version in 2..=35 {
+            let command = match version % 4 {
+                0 | 2 => { TodoCommand::RenameTodo { name: format!("name {version}") } }
+                1 => { TodoCommand::ReopenTodo }
+                _ => { TodoCommand::CompleteTodo { cascade: false } }
+            };
+            service.update_task(id, command, Some(version - 1), &metadata).await.unwrap();
+
+            let loaded = service.load(id).await.unwrap();
+            let events = service.todos.history(id).await.unwrap().into_iter().map(|envelope| envelope.event).collect();
+            assert_eq!(loaded, TodoAggregate::from_events(events));
+        }
+
+        let snapshot = service.event_store().get_snapshot(&TodoAggregate::stream_name(id)).await.unwrap().unwrap();
+        assert_eq!(snapshot.version, 30);
+        assert_eq!(service.load(id).await.unwrap().version(), 35);
+        assert_eq!(service.get_task_by_id(id).await.unwrap().version, 35);
+    }
+
+    #[rocket::async_test]
+    async fn stale_appends_are_conflicts() {
+        let service = service(Box::new(SystemClock)).await;
+        let metadata = EventMetadata::default();
+        let id = service.create_task("draft".to_string(), &metadata).await.unwrap().id;
+        service.update_task(id, TodoCommand::RenameTodo { name: "first".to_string() }, Some(1), &metadata).await.unwrap();
+
+        let stale = service.update_task(id, TodoCommand::RenameTodo { name: "second".to_string() }, Some(1), &metadata).await;
+        assert!(matches!(stale, Err(TodoServiceErr::Conflict(_))));
+        assert_eq!(service.get_task_by_id(id).await.unwrap().name, "first");
+
+        let raced = EventStoreErr::ConcurrencyErr { stream: TodoAggregate::stream_name(id), expected_version: 1 };
+        assert!(matches!(TodoServiceErr::from(raced), TodoServiceErr::Conflict(_)));
+    }
+
+    #[rocket::async_test]
+    async fn reads_todos_at_a_version_or_time() {
+        let service = service(Box::new(SystemClock)).await;
+        let metadata = EventMetadata::default();
+        let before = Utc::now();
+        let id = service.create_task("first".to_string(), &metadata).await.unwrap().id;
+        service.update_task(id, TodoCommand::RenameTodo { name: "second".to_string() }, None, &metadata).await.unwrap();
+
+        assert_eq!(service.get_task_at(id, PointInTime::Version(1)).await.unwrap().name, "first");
+        assert_eq!(service.get_task_at(id, PointInTime::Timestamp(Utc::now())).await.unwrap().name, "second");
+        for at in [PointInTime::Version(3), PointInTime::Version(0), PointInTime::Timestamp(before - Duration::seconds(1))] {
+            assert!(matches!(service.get_task_at(id, at).await, Err(TodoServiceErr::NotFound(_))));
+        }
+    }
+
+    #[rocket::async_test]
+    async fn events_without_a_timestamp_count_as_recorded_at_the_epoch() {
+        let service = service(Box::new(SystemClock)).await;
+        let id = Guid::new();
+        let mut legacy = EventEnvelope::new(TodoEvent::Create { name: "legacy".to_string(), id }, &EventMetadata::default());
+        legacy.recorded_at = DateTime::UNIX_EPOCH;
+        service.event_store().append(&TodoAggregate::stream_name(id), 0, vec![legacy.to_raw().unwrap()]).await.unwrap();
+        service.update_task(id, TodoCommand::RenameTodo { name: "current".to_string() }, None, &EventMetadata::default()).await.unwrap();
+
+        let last_year = Utc::now() - Duration::days(365);
+        assert_eq!(service.get_task_at(id, PointInTime::Timestamp(last_year)).await.unwrap().name, "legacy");
+        let before_epoch = DateTime::UNIX_EPOCH - Duration::seconds(1);
+        assert!(matches!(service.get_task_at(id, PointInTime::Timestamp(before_epoch)).await, Err(TodoServiceErr::NotFound(_))));
+    }
+
+    async fn names(service: &TodoService, filter: TodoFilter) -> Vec<String> {
+        let mut names: Vec<String> = service.list_tasks(&filter).await.unwrap().into_iter().map(|todo| todo.name).collect();
+        names.sort();
+        names
+    }
+
+    #[rocket::async_test]
+    async fn filters_todos_by_due_date() {
+        let now = Utc.with_ymd_and_hms(2024, 5, 1, 12, 0, 0).unwrap();
+        let service = service(Box::new(FixedClock(now))).await;
+        let metadata = EventMetadata::default();
+        for (name, due) in [("late", Some(-1)), ("tomorrow", Some(1)), ("next month", Some(30)), ("whenever", None)] {
+            let id = service.create_task(name.to_string(), &metadata).await.unwrap().id;
+            if let Some(days) = due {
+                service.update_task(id, TodoCommand::SetDueDate { due: now + Duration::days(days) }, None, &metadata).await.unwrap();
+            }
+        }
+
+        assert_eq!(names(&service, TodoFilter::default()).await.len(), 4);
+        assert_eq!(names(&service, TodoFilter { overdue: Some(true), ..TodoFilter::default() }).await, vec!["late"]);
+        assert_eq!(names(&service, TodoFilter { overdue: Some(false), ..TodoFilter::default() }).await, vec!["next month", "tomorrow", "whenever"]);
+        assert_eq!(names(&service, TodoFilter { due_within: Some(Duration::weeks(1)), ..TodoFilter::default() }).await, vec!["tomorrow"]);
+        assert_eq!(names(&service, TodoFilter { due_before: Some(now + Duration::days(2)), ..TodoFilter::default() }).await, vec!["late", "tomorrow"]);
+    }
+
+    #[rocket::async_test]
+    async fn moving_a_todo_updates_the_todo_and_both_lists() {
+        let service = service(Box::new(SystemClock)).await;
+        let metadata = EventMetadata::default();
+        let home = service.create_list("home".to_string(), &metadata).await.unwrap().id;
+        let work = service.create_list("work".to_string(), &metadata).await.unwrap().id;
+        let id = service.create_task("paint fence".to_string(), &metadata).await.unwrap().id;
+
+        service.move_task(id, Some(home), &metadata).await.unwrap();
+        let todo = service.move_task(id, Some(work), &metadata).await.unwrap();
+
+        assert_eq!(todo.list, Some(work));
+        assert!(service.get_list(home).await.unwrap().todos.is_empty());
+        assert_eq!(service.get_list(work).await.unwrap().todos, vec![id]);
+        assert_eq!(service.list_todos(work).await.unwrap()[0].list, Some(work));
+
+        service.update_list(home, TodoListCommand::ArchiveList, None, &metadata).await.unwrap();
+        assert!(matches!(service.move_task(id, Some(home), &metadata).await, Err(TodoServiceErr::Rejected(_))));
+        assert_eq!(service.get_list(work).await.unwrap().todos, vec![id]);
+
+        service.update_task(id, TodoCommand::DeleteTodo, None, &metadata).await.unwrap();
+        assert!(service.get_list(work).await.unwrap().todos.is_empty());
+    }
+
+    #[rocket::async_test]
+    async fn list_follow_ups_do_not_fail_a_stored_change() {
+        let service = service(Box::new(SystemClock)).await;
+        let metadata = EventMetadata::default();
+        let home = service.create_list("home".to_string(), &metadata).await.unwrap().id;
+        let work = service.create_list("work".to_string(), &metadata).await.unwrap().id;
+        let id = service.create_task("paint fence".to_string(), &metadata).await.unwrap().id;
+        service.move_task(id, Some(home), &metadata).await.unwrap();
+
+        // The list no longer holds the todo, so taking it out again is rejected.
+        service.update_list(home, TodoListCommand::RemoveTodo { todo: id }, None, &metadata).await.unwrap();
+        assert_eq!(service.move_task(id, Some(work), &metadata).await.unwrap().list, Some(work));
+
+        service.update_list(work, TodoListCommand::RemoveTodo { todo: id }, None, &metadata).await.unwrap();
+        assert!(service.update_task(id, TodoCommand::DeleteTodo, None, &metadata).await.unwrap().is_deleted);
+    }
+
+    #[rocket::async_test]
+    async fn subtasks_roll_up_into_their_parent() {
+        let service = service(Box::new(SystemClock)).await;
+        let metadata = EventMetadata::default();
+        let mut ids = vec![];
+        for name in ["move house", "pack", "pack books", "pack plates"] {
+            ids.push(service.create_task(name.to_string(), &metadata).await.unwrap().id);
+        }
+        let (root, pack, books, plates) = (ids[0], ids[1], ids[2], ids[3]);
+        for (child, parent) in [(pack, root), (books, pack), (plates, pack)] {
+            service.update_task(child, TodoCommand::AttachToParent { parent }, None, &metadata).await.unwrap();
+        }
+        service.update_task(books, TodoCommand::CompleteTodo { cascade: false }, None, &metadata).await.unwrap();
+
+        let tree = service.get_task_tree(root).await.unwrap();
+        assert_eq!((tree.completion, tree.children[0].completion), (Some(50), Some(50)));
+        assert_eq!(tree.children[0].children.len(), 2);
+
+        let cycle = service.update_task(root, TodoCommand::AttachToParent { parent: plates }, None, &metadata).await;
+        assert!(matches!(cycle, Err(TodoServiceErr::Rejected(_))));
+        let open = service.update_task(root, TodoCommand::CompleteTodo { cascade: false }, None, &metadata).await;
+        assert!(matches!(open, Err(TodoServiceErr::Rejected(_))));
+
+        service.update_task(root, TodoCommand::CompleteTodo { cascade: true }, None, &metadata).await.unwrap();
+        for id in ids {
+            assert_eq!(service.get_task_by_id(id).await.unwrap().status, Status::Complete);
+        }
+    }
+
+    #[rocket::async_test]
+    async fn cascades_check_every_subtask_before_completing_any() {
+        let service = service(Box::new(SystemClock)).await;
+        let metadata = EventMetadata::default();
+        let mut ids = vec![];
+        for name in ["move house", "pack", "hire van"] {
+            ids.push(service.create_task(name.to_string(), &metadata).await.unwrap().id);
+        }
+        let (root, pack, van) = (ids[0], ids[1], ids[2]);
+        for child in [pack, van] {
+            service.update_task(child, TodoCommand::AttachToParent { parent: root }, None, &metadata).await.unwrap();
+        }
+        // The read model still shows the van as open while its stream has it blocked.
+        let stale = service.todo_repo.get_by_id(van).await.unwrap();
+        service.update_task(van, TodoCommand::BlockTodo, None, &metadata).await.unwrap();
+        service.todo_repo.upsert(stale).await.unwrap();
+
+        let cascade = service.update_task(root, TodoCommand::CompleteTodo { cascade: true }, None, &metadata).await;
+        assert!(matches!(cascade, Err(TodoServiceErr::Rejected(_))));
+        assert_eq!(service.get_task_by_id(pack).await.unwrap().status, Status::Incomplete);
+    }
+
+    #[rocket::async_test]
+    async fn cascades_leave_subtasks_alone_when_the_parent_cannot_complete() {
+        let service = service(Box::new(SystemClock)).await;
+        let metadata = EventMetadata::default();
+        let root = service.create_task("move house".to_string(), &metadata).await.unwrap().id;
+        let pack = service.create_task("pack".to_string(), &metadata).await.unwrap().id;
+        service.update_task(pack, TodoCommand::AttachToParent { parent: root }, None, &metadata).await.unwrap();
+        service.update_task(root, TodoCommand::BlockTodo, None, &metadata).await.unwrap();
+
+        let cascade = service.update_task(root, TodoCommand::CompleteTodo { cascade: true }, None, &metadata).await;
+        assert!(matches!(cascade, Err(TodoServiceErr::Rejected(_))));
+        assert_eq!(service.get_task_by_id(pack).await.unwrap().status, Status::Incomplete);
+    }
+
+    #[rocket::async_test]
+    async fn restored_todos_leave_the_trash_and_rejoin_their_list() {
+        let service = service(Box::new(SystemClock)).await;
+        let metadata = EventMetadata::default();
+        let (home, work) = (
+            service.create_list("home".to_string(), &metadata).await.unwrap().id,
+            service.create_list("work".to_string(), &metadata).await.unwrap().id,
+        );
+        let (fence, report) = (
+            service.create_task("paint fence".to_string(), &metadata).await.unwrap().id,
+            service.create_task("write report".to_string(), &metadata).await.unwrap().id,
+        );
+        service.move_task(fence, Some(home), &metadata).await.unwrap();
+        service.move_task(report, Some(work), &metadata).await.unwrap();
+        for id in [fence, report] {
+            service.update_task(id, TodoCommand::DeleteTodo, None, &metadata).await.unwrap();
+        }
+
+        assert!(service.list_tasks(&TodoFilter::default()).await.unwrap().is_empty());
+        assert_eq!(service.trash().await.unwrap().len(), 2);
+
+        service.update_list(work, TodoListCommand::ArchiveList, None, &metadata).await.unwrap();
+        let fence = service.update_task(fence, TodoCommand::RestoreTodo, None, &metadata).await.unwrap();
+        let report = service.update_task(report, TodoCommand::RestoreTodo, None, &metadata).await.unwrap();
+
+        assert_eq!((fence.list, report.list), (Some(home), None));
+        assert_eq!(service.get_list(home).await.unwrap().todos, vec![fence.id]);
+        assert_eq!(names(&service, TodoFilter::default()).await, vec!["paint fence", "write report"]);
+        assert!(service.trash().await.unwrap().is_empty());
+        let again = service.update_task(fence.id, TodoCommand::RestoreTodo, None, &metadata).await;
+        assert!(matches!(again, Err(TodoServiceErr::Rejected(_))));
+    }
 }
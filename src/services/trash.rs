@@ -0,0 +1,181 @@
+use std::sync::Arc;
+use std::time::Duration;
+use chrono::{DateTime, Utc};
+use rocket::Shutdown;
+use rocket::tokio::{self, select};
+use crate::guid::Guid;
+use crate::services::aggregate::{Aggregate, TodoAggregate};
+use crate::services::clock::Clock;
+use crate::services::data::TodoRepository;
+use crate::services::event_store::EventStoreErr;
+use crate::services::repository::EventSourcedRepository;
+use crate::services::todo::TodoServiceErr;
+
+#[derive(Debug, Clone, Copy)]
+pub struct TrashSettings {
+    /// How long a deleted todo can still be restored before it is purged.
+    pub retention: chrono::Duration,
+    pub purge_interval: Duration,
+}
+
+/// Permanently removes the streams of todos that were deleted longer ago than the retention.
+pub struct TrashPurger {
+    todo_repo: Arc<dyn TodoRepository>,
+    todos: EventSourcedRepository<TodoAggregate>,
+    settings: TrashSettings,
+    clock: Box<dyn Clock>,
+}
+
+impl TrashPurger {
+    pub fn new(todo_repo: Arc<dyn TodoRepository>, todos: EventSourcedRepository<TodoAggregate>, settings: TrashSettings, clock: Box<dyn Clock>) -> TrashPurger {
+        TrashPurger {
+            todo_repo,
+            todos,
+            settings,
+            clock,
+        }
+    }
+
+    /// Purges the expired todos and returns how many were removed. The read model only names
+    /// the candidates, since it may not have seen a restore yet.
+    pub async fn purge(&self) -> Result<usize, TodoServiceErr> {
+        let expired_before = self.clock.now() - self.settings.retention;
+        let mut purged = 0;
+
+        for todo in self.todo_repo.list_deleted().await? {
+            if todo.deleted_at.is_some_and(|deleted_at| deleted_at < expired_before) && self.purge_todo(todo.id, expired_before).await? {
+                purged += 1;
+            }
+        }
+
+        Ok(purged)
+    }
+
+    /// Deletes the stream of todo `id` if its events show it deleted before `expired_before`.
+    /// The stream is only deleted at the version checked, so a restore appended in the
+    /// meantime wins. It goes before the read model entry, so a todo whose entry could not be
+    /// removed is cleaned up next time.
+    async fn purge_todo(&self, id: Guid, expired_before: DateTime<Utc>) -> Result<bool, TodoServiceErr> {
+        let event_repo = self.todos.event_store();
+        let stream = TodoAggregate::stream_name(id);
+        let todo = match self.todos.load(id).await {
+            Ok(todo) => { todo }
+            Err(EventStoreErr::NotFound(_)) => {
+                self.todo_repo.delete(id).await?;
+                return Ok(false);
+            }
+            Err(err) => { return Err(err.into()); }
+        };
+        if !todo.is_deleted {
+            return Ok(false);
+        }
+        // The last event of a deleted todo is its `Delete`.
+        let deleted_at = event_repo
+            .get_events_after(&stream, todo.version() - 1).await?
+            .last()
+            .map(|envelope| envelope.recorded_at);
+        if deleted_at.is_none_or(|deleted_at| deleted_at >= expired_before) {
+            return Ok(false);
+        }
+
+        match event_repo.delete_stream(&stream, todo.version()).await {
+            Ok(()) => {}
+            Err(EventStoreErr::ConcurrencyErr { .. }) => { return Ok(false); }
+            Err(err) => { return Err(err.into()); }
+        }
+        self.todo_repo.delete(id).await?;
+
+        Ok(true)
+    }
+
+    /// Spawns a task that purges every `purge_interval` until the server shuts down.
+    pub fn start(self, mut shutdown: Shutdown) {
+        tokio::spawn(async move {
+            loop {
+                match self.purge().await {
+                    Ok(0) => {}
+                    Ok(purged) => { log::info!("Purged {} deleted todos", purged); }
+                    Err(err) => { log::warn!("Could not purge deleted todos: {}", err); }
+                }
+
+                select! {
+                    _ = tokio::time::sleep(self.settings.purge_interval) => {}
+                    _ = &mut shutdown => { break; }
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+    use chrono::Utc;
+    use crate::guid::Guid;
+    use crate::routes::todo::Todo;
+    use crate::services::aggregate::{Aggregate, TodoAggregate, TodoCommand};
+    use crate::services::clock::{FixedClock, SystemClock};
+    use crate::services::data::{InMemoryTodoRepository, TodoRepository};
+    use crate::services::event_store::{EventMetadata, EventStore, InMemoryEventStore};
+    use crate::services::repository::EventSourcedRepository;
+    use crate::services::todo::{TodoService, TodoServiceErr};
+    use crate::services::trash::{TrashPurger, TrashSettings};
+
+    #[rocket::async_test]
+    async fn purges_todos_deleted_before_the_retention_period() {
+        let service = TodoService::init(
+            Box::new(InMemoryTodoRepository::new()),
+            Box::new(InMemoryEventStore::default()),
+            0,
+            Box::new(SystemClock),
+        ).await;
+        let metadata = EventMetadata::default();
+        let mut deleted = vec![];
+        for name in ["old", "older"] {
+            let id = service.create_task(name.to_string(), &metadata).await.unwrap().id;
+            service.update_task(id, TodoCommand::DeleteTodo, None, &metadata).await.unwrap();
+            deleted.push(id);
+        }
+        let settings = TrashSettings { retention: chrono::Duration::days(30), purge_interval: Duration::from_secs(60) };
+
+        let fresh = service.purger(settings, Box::new(FixedClock(Utc::now())));
+        assert_eq!(fresh.purge().await.unwrap(), 0);
+        assert_eq!(service.trash().await.unwrap().len(), 2);
+
+        // The todo deleted last is purged as well.
+        let expired = service.purger(settings, Box::new(FixedClock(Utc::now() + chrono::Duration::days(31))));
+        assert_eq!(expired.purge().await.unwrap(), 2);
+        assert!(service.trash().await.unwrap().is_empty());
+        for id in deleted {
+            assert!(matches!(service.get_task_by_id(id).await, Err(TodoServiceErr::NotFound(_))));
+            assert!(service.update_task(id, TodoCommand::RestoreTodo, None, &metadata).await.is_err());
+        }
+    }
+
+    #[rocket::async_test]
+    async fn keeps_todos_restored_since_the_read_model_saw_them_deleted() {
+        let event_repo: Arc<dyn EventStore> = Arc::new(InMemoryEventStore::default());
+        let todo_repo: Arc<dyn TodoRepository> = Arc::new(InMemoryTodoRepository::new());
+        let todos = EventSourcedRepository::<TodoAggregate>::new(event_repo.clone(), 0);
+        let id = Guid::new();
+        let metadata = EventMetadata::default();
+        todos
+            .create(id, &metadata, |agg: &TodoAggregate| Ok::<_, TodoServiceErr>(agg.decide(TodoCommand::CreateTodo { id, name: "old".to_string() })?)).await
+            .unwrap();
+        for command in [TodoCommand::DeleteTodo, TodoCommand::RestoreTodo] {
+            todos
+                .execute(id, &metadata, |agg: &TodoAggregate| Ok::<_, TodoServiceErr>(agg.decide(command)?)).await
+                .unwrap();
+        }
+        // The projection has not caught up with the restore.
+        let mut stale = Todo::from_agg(todos.load(id).await.unwrap());
+        stale.deleted_at = Some(Utc::now());
+        todo_repo.upsert(stale).await.unwrap();
+
+        let settings = TrashSettings { retention: chrono::Duration::days(30), purge_interval: Duration::from_secs(60) };
+        let purger = TrashPurger::new(todo_repo, todos.clone(), settings, Box::new(FixedClock(Utc::now() + chrono::Duration::days(31))));
+        assert_eq!(purger.purge().await.unwrap(), 0);
+        assert_eq!(event_repo.get(&TodoAggregate::stream_name(id)).await.unwrap().len(), 3);
+    }
+}
@@ -0,0 +1,135 @@
+use serde_json::{Map, Value};
+
+/// Schema version written with every new event.
+///
+/// 1. Anything stored before events carried a schema version: bare `TodoEvent`s from before
+///    envelopes, and envelopes whose id and timestamp may be missing.
+/// 2. Every envelope field is present.
+pub const CURRENT_SCHEMA_VERSION: u32 = 2;
+
+/// Turns a stored event of one schema version into the shape of the next version.
+type Upcaster = fn(Map<String, Value>) -> Result<Map<String, Value>, String>;
+
+/// The upcaster reading each schema version older than `CURRENT_SCHEMA_VERSION`. A breaking
+/// change to `TodoEvent` or `EventEnvelope` bumps the current version and adds an entry here
+/// that rewrites the previous version's payload.
+const UPCASTERS: &[(u32, Upcaster)] = &[
+    (1, fill_in_envelope),
+];
+
+/// Brings a stored event up to `CURRENT_SCHEMA_VERSION` by running every upcaster from its
+/// own version onwards. Events without a `schema_version` are version 1.
+pub fn upcast(value: Value) -> Result<Value, String> {
+    let mut event = match value {
+        Value::Object(event) => { event }
+        _ => { return Err("Stored event is not an object".to_string()); }
+    };
+    let mut version = match event.get("schema_version") {
+        None => { 1 }
+        Some(version) => {
+            version
+                .as_u64()
+                .ok_or_else(|| format!("Invalid schema_version {}", version))? as u32
+        }
+    };
+    if version > CURRENT_SCHEMA_VERSION {
+        return Err(format!("Event schema version {} is newer than this build supports", version));
+    }
+
+    while version < CURRENT_SCHEMA_VERSION {
+        let upcaster = UPCASTERS
+            .iter()
+            .find(|(from, _)| *from == version)
+            .map(|(_, upcaster)| upcaster)
+            .ok_or_else(|| format!("No upcaster for event schema version {}", version))?;
+        event = upcaster(event)?;
+        version += 1;
+    }
+    event.insert("schema_version".to_string(), Value::from(CURRENT_SCHEMA_VERSION));
+
+    Ok(Value::Object(event))
+}
+
+/// Events from before envelopes read as the empty id at the Unix epoch, without metadata.
+fn fill_in_envelope(mut event: Map<String, Value>) -> Result<Map<String, Value>, String> {
+    let defaults = [
+        ("event_id", Value::from("00000000-0000-0000-0000-000000000000")),
+        ("recorded_at", Value::from("1970-01-01T00:00:00Z")),
+        ("actor", Value::Null),
+        ("correlation_id", Value::Null),
+        ("causation_id", Value::Null),
+    ];
+    for (key, default) in defaults {
+        event.entry(key).or_insert(default);
+    }
+
+    Ok(event)
+}
+
+#[cfg(test)]
+mod tests {
+    use mongodb::bson;
+    use crate::guid::Guid;
+    use crate::routes::todo::Status;
+    use crate::services::aggregate::TodoEvent;
+    use crate::services::event_store::EventEnvelope;
+    use crate::services::upcast::{CURRENT_SCHEMA_VERSION, UPCASTERS};
+
+    fn expected_events() -> Vec<TodoEvent> {
+        let id = Guid::from_str("5b0c5a8e-3a4f-4b52-9d2e-8f0a6c1d2e3f").unwrap();
+        vec![
+            TodoEvent::Create { name: "Buy milk".to_string(), id },
+            TodoEvent::ChangeName { new_name: "Buy oat milk".to_string(), version: 2 },
+            TodoEvent::ChangeStatus { status: Status::Complete, version: 3 },
+            TodoEvent::Delete { version: 4 },
+        ]
+    }
+
+    fn load(fixture: &str) -> Vec<EventEnvelope<TodoEvent>> {
+        let envelopes: Vec<EventEnvelope<TodoEvent>> = serde_json::from_str(fixture).unwrap();
+        assert!(envelopes.iter().all(|envelope| envelope.schema_version == CURRENT_SCHEMA_VERSION));
+        assert_eq!(envelopes.iter().map(|envelope| envelope.event.clone()).collect::<Vec<_>>(), expected_events());
+
+        envelopes
+    }
+
+    #[test]
+    fn every_old_schema_version_has_an_upcaster() {
+        for version in 1..CURRENT_SCHEMA_VERSION {
+            assert!(UPCASTERS.iter().any(|(from, _)| *from == version), "missing upcaster for {}", version);
+        }
+    }
+
+    #[test]
+    fn reads_v1_events_stored_before_envelopes() {
+        let envelopes = load(include_str!("../../tests/fixtures/events/v1-bare.json"));
+        assert!(envelopes.iter().all(|envelope| envelope.event_id == Guid::empty() && envelope.actor.is_none()));
+    }
+
+    #[test]
+    fn reads_v1_envelopes() {
+        let envelopes = load(include_str!("../../tests/fixtures/events/v1-envelopes.json"));
+        assert_eq!(envelopes[0].actor.as_deref(), Some("ada"));
+        assert_ne!(envelopes[0].event_id, Guid::empty());
+    }
+
+    #[test]
+    fn reads_v2_envelopes() {
+        let envelopes = load(include_str!("../../tests/fixtures/events/v2.json"));
+        assert_eq!(envelopes[3].causation_id.as_deref(), Some("c0ffee"));
+    }
+
+    #[test]
+    fn reads_v1_events_stored_as_bson() {
+        let json: serde_json::Value = serde_json::from_str(include_str!("../../tests/fixtures/events/v1-bare.json")).unwrap();
+        let stored = bson::to_bson(&json).unwrap();
+        let envelopes: Vec<EventEnvelope<TodoEvent>> = bson::from_bson(stored).unwrap();
+        assert_eq!(envelopes.into_iter().map(|envelope| envelope.event).collect::<Vec<_>>(), expected_events());
+    }
+
+    #[test]
+    fn rejects_events_from_a_newer_schema() {
+        let newer = format!(r#"{{"schema_version": {}, "type": "Delete", "version": 2}}"#, CURRENT_SCHEMA_VERSION + 1);
+        assert!(serde_json::from_str::<EventEnvelope<TodoEvent>>(&newer).is_err());
+    }
+}